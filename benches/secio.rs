@@ -0,0 +1,70 @@
+#[macro_use]
+extern crate criterion;
+extern crate futures_await as futures;
+extern crate libp2p_crypto as crypto;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::fmt;
+
+use criterion::Criterion;
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::{flush, read_exact, write_all};
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+use secio::HandshakeConfig;
+use secio::testing;
+
+const FRAME_SIZES: &[usize] = &[64, 1024, 16 * 1024, 64 * 1024];
+
+struct Suite {
+    cipher: CipherAlgorithm,
+    hash: HashAlgorithm,
+    size: usize,
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}/{}", self.cipher, self.hash, self.size)
+    }
+}
+
+/// Full handshake between two RSA identities, per key exchange curve.
+fn handshake(c: &mut Criterion) {
+    let curves: Vec<CurveAlgorithm> = CurveAlgorithm::all().iter().cloned().collect();
+    c.bench_function_over_inputs("handshake", |b, &curve| {
+        let mut core = Core::new().unwrap();
+        let config = HandshakeConfig::new().curves(vec![curve]);
+        b.iter(|| core.run(testing::connect(config.clone(), config.clone())).unwrap());
+    }, curves);
+}
+
+/// Sending a single frame from one side and reading it on the other, per
+/// cipher and hash.
+fn throughput(c: &mut Criterion) {
+    let mut suites = Vec::new();
+    for &cipher in CipherAlgorithm::all().iter() {
+        for &hash in HashAlgorithm::all().iter() {
+            for &size in FRAME_SIZES {
+                suites.push(Suite { cipher, hash, size });
+            }
+        }
+    }
+
+    c.bench_function_over_inputs("throughput", |b, suite| {
+        let mut core = Core::new().unwrap();
+        let config = HandshakeConfig::new().ciphers(vec![suite.cipher]).hashes(vec![suite.hash]);
+        let (mut alice, mut bob) = core.run(testing::connect(config.clone(), config)).unwrap();
+        let data = vec![0; suite.size];
+        b.iter(|| {
+            let write = write_all(&mut alice, &data[..]).and_then(|(alice, _)| flush(alice));
+            let read = read_exact(&mut bob, vec![0; suite.size]);
+            core.run(write.join(read)).unwrap();
+        });
+    }, suites);
+}
+
+criterion_group!(benches, handshake, throughput);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#!/bin/sh
+# Build every combination of the logging features, run the test suite, then
+# the tests needing the tools and tracing features.
+set -ex
+
+cargo build --no-default-features
+cargo build --no-default-features --features slog
+cargo build --no-default-features --features tracing
+cargo build --no-default-features --features "slog tracing"
+
+cargo test
+cargo test --features tools --test inspect
+cargo test --no-default-features --features "testing tracing" --test tracing
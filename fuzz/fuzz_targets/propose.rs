@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate libp2p_secio as secio;
+
+fuzz_target!(|data: &[u8]| {
+    secio::fuzz::propose(data);
+});
@@ -0,0 +1,255 @@
+//! Loopback TCP load generator for sizing nodes.
+//!
+//! Usage: `secio-bench <handshakes|throughput|allocations> [pairs] [seconds]`
+//!
+//! Runs `pairs` concurrent client/server pairs (default 4) for `seconds`
+//! (default 5) per suite. `handshakes` repeatedly connects and handshakes,
+//! reporting the rate and latency per curve, `throughput` streams data over
+//! a single connection per pair, reporting MB/s per cipher and hash.
+//!
+//! `allocations` ignores `pairs` and `seconds`, instead sending a fixed
+//! number of frames of each size over an in-memory pipe and reporting the
+//! heap allocations made per frame when writing and reading.
+
+#![feature(generators)]
+#![feature(proc_macro)]
+
+extern crate futures_await as futures;
+extern crate libp2p_crypto as crypto;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Stream};
+use futures::future::{self, join_all};
+use futures::prelude::{await, async};
+use identity::PeerId;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncWrite;
+use tokio_io::io::{flush, read, read_exact, write_all};
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+use secio::{HandshakeConfig, SecStream};
+use secio::testing::{self, alice, bob, parts};
+
+const CHUNK: usize = 64 * 1024;
+
+/// Frame sizes and how many of each to send when counting allocations.
+const ALLOCATION_SIZES: &[usize] = &[64, 1024, 16 * 1024, 64 * 1024];
+const ALLOCATION_FRAMES: usize = 1000;
+
+/// Counts every allocation made by the process, so the record layer's
+/// allocations per frame can be reported.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Run `f`, returning its result and the allocations made while it ran.
+fn allocations<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let result = f();
+    (result, ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+fn shutdown<W: AsyncWrite + 'static>(writer: W) -> Box<Future<Item=W, Error=io::Error>> {
+    let mut writer = Some(writer);
+    Box::new(future::poll_fn(move || {
+        match writer.as_mut().expect("polled after completion").shutdown()? {
+            Async::Ready(()) => Ok(Async::Ready(writer.take().unwrap())),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }))
+}
+
+/// Connect a fresh pair over loopback and handshake, returning the client
+/// and server ends and how long it took.
+#[async]
+fn pair(handle: Handle, config: HandshakeConfig) -> io::Result<(SecStream<TcpStream>, SecStream<TcpStream>, Duration)> {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle)?;
+    let addr = listener.local_addr()?;
+
+    let start = Instant::now();
+    let accept = listener.incoming().into_future()
+        .map_err(|(err, _)| err)
+        .and_then(|(socket, _)| socket.map(|(socket, _)| socket).ok_or_else(|| io::Error::new(io::ErrorKind::Other, "listener closed")));
+    let (client, server) = await!(TcpStream::connect(&addr, &handle).join(accept))?;
+
+    let client = secio::handshake(config.clone(), parts(client), alice(), PeerId::Unknown);
+    let server = secio::handshake(config, parts(server), bob(), PeerId::Unknown);
+    let ((_, client), (_, server)) = await!(client.join(server))?;
+    Ok((client, server, start.elapsed()))
+}
+
+#[async]
+fn handshakes(handle: Handle, config: HandshakeConfig, until: Instant) -> io::Result<Vec<Duration>> {
+    let mut latencies = Vec::new();
+    while Instant::now() < until {
+        let (_, _, latency) = await!(pair(handle.clone(), config.clone()))?;
+        latencies.push(latency);
+    }
+    Ok(latencies)
+}
+
+#[async]
+fn send(mut stream: SecStream<TcpStream>, until: Instant) -> io::Result<()> {
+    let data = vec![0; CHUNK];
+    while Instant::now() < until {
+        let (written, _) = await!(write_all(stream, data.clone()))?;
+        stream = await!(flush(written))?;
+    }
+    await!(shutdown(stream))?;
+    Ok(())
+}
+
+#[async]
+fn receive(mut stream: SecStream<TcpStream>) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK];
+    let mut received = 0;
+    loop {
+        let (next, next_buf, len) = await!(read(stream, buf))?;
+        if len == 0 {
+            return Ok(received);
+        }
+        received += len as u64;
+        stream = next;
+        buf = next_buf;
+    }
+}
+
+#[async]
+fn throughput(handle: Handle, config: HandshakeConfig, until: Instant) -> io::Result<u64> {
+    let (client, server, _) = await!(pair(handle, config))?;
+    let ((), received) = await!(send(client, until).join(receive(server)))?;
+    Ok(received)
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    seconds(sorted[(sorted.len() - 1) * percentile / 100]) * 1000.0
+}
+
+fn run_handshakes(core: &mut Core, pairs: usize, duration: Duration) -> io::Result<()> {
+    println!("{:<12} {:>10} {:>12} {:>10} {:>10}", "curve", "handshakes", "per second", "p50 ms", "p99 ms");
+    for &curve in CurveAlgorithm::all().iter() {
+        let config = HandshakeConfig::new().curves(vec![curve]);
+        let until = Instant::now() + duration;
+        let workers = (0..pairs).map(|_| handshakes(core.handle(), config.clone(), until));
+        let mut latencies: Vec<Duration> = core.run(join_all(workers))?.into_iter().flat_map(|latencies| latencies).collect();
+        latencies.sort();
+        println!("{:<12} {:>10} {:>12.1} {:>10.2} {:>10.2}",
+            curve.to_string(),
+            latencies.len(),
+            latencies.len() as f64 / seconds(duration),
+            percentile(&latencies, 50),
+            percentile(&latencies, 99));
+    }
+    Ok(())
+}
+
+fn run_throughput(core: &mut Core, pairs: usize, duration: Duration) -> io::Result<()> {
+    println!("{:<12} {:<12} {:>10}", "cipher", "hash", "MB/s");
+    for &cipher in CipherAlgorithm::all().iter() {
+        for &hash in HashAlgorithm::all().iter() {
+            let config = HandshakeConfig::new().ciphers(vec![cipher]).hashes(vec![hash]);
+            let start = Instant::now();
+            let until = start + duration;
+            let workers = (0..pairs).map(|_| throughput(core.handle(), config.clone(), until));
+            let received: u64 = core.run(join_all(workers))?.into_iter().sum();
+            let elapsed = seconds(start.elapsed());
+            println!("{:<12} {:<12} {:>10.1}", cipher.to_string(), hash.to_string(), received as f64 / elapsed / 1e6);
+        }
+    }
+    Ok(())
+}
+
+fn run_allocations(core: &mut Core) -> io::Result<()> {
+    println!("{:<12} {:<12} {:>8} {:>14} {:>14}", "cipher", "hash", "size", "write allocs", "read allocs");
+    for &cipher in CipherAlgorithm::all().iter() {
+        for &hash in HashAlgorithm::all().iter() {
+            let config = HandshakeConfig::new().ciphers(vec![cipher]).hashes(vec![hash]);
+            for &size in ALLOCATION_SIZES {
+                let (mut client, mut server) = core.run(testing::connect(config.clone(), config.clone()))?;
+                let data = vec![0; size];
+                let mut buf = vec![0; size];
+                let (mut written, mut read) = (0, 0);
+                for _ in 0..ALLOCATION_FRAMES {
+                    let (result, count) = allocations(|| core.run(write_all(&mut client, &data[..]).and_then(|(client, _)| flush(client))));
+                    result?;
+                    written += count;
+                    let (result, count) = allocations(|| core.run(read_exact(&mut server, &mut buf[..])));
+                    result?;
+                    read += count;
+                }
+                println!("{:<12} {:<12} {:>8} {:>14.2} {:>14.2}",
+                    cipher.to_string(),
+                    hash.to_string(),
+                    size,
+                    written as f64 / ALLOCATION_FRAMES as f64,
+                    read as f64 / ALLOCATION_FRAMES as f64);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("usage: secio-bench <handshakes|throughput|allocations> [pairs] [seconds]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 3 {
+        usage();
+    }
+    let pairs = args.get(1).map_or(Some(4), |arg| arg.parse().ok()).unwrap_or_else(|| usage());
+    let duration = Duration::from_secs(args.get(2).map_or(Some(5), |arg| arg.parse().ok()).unwrap_or_else(|| usage()));
+
+    let result = Core::new().and_then(|mut core| {
+        match &*args[0] {
+            "handshakes" => run_handshakes(&mut core, pairs, duration),
+            "throughput" => run_throughput(&mut core, pairs, duration),
+            "allocations" => run_allocations(&mut core),
+            _ => usage(),
+        }
+    });
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Like netcat, but over secio.
+//!
+//! Usage: `secio-cat [-l] <address> <private key> <public key>`
+//!
+//! Connects to, or with `-l` accepts a single connection on, a TCP address
+//! and performs the secio handshake using the RSA identity from the given
+//! DER encoded key files. Once connected stdin is sent to the peer and
+//! everything received is written to stdout. The end of stdin shuts down
+//! our half of the connection, exiting once the peer closes its half.
+//!
+//! There's no ephemeral identity option, as libp2p-identity can only load
+//! existing keys, not generate them.
+
+extern crate bytes;
+extern crate futures_await as futures;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+
+use bytes::BytesMut;
+use futures::{Future, Poll, Sink, Stream};
+use futures::future;
+use futures::sync::mpsc;
+use identity::{HostId, PeerId};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::FramedParts;
+use tokio_io::io::{copy, flush, write_all};
+
+use secio::HandshakeConfig;
+
+/// Blocking stdout, good enough as the event loop has nothing else to do
+/// while it's waiting.
+struct Stdout(io::Stdout);
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncWrite for Stdout {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(().into())
+    }
+}
+
+/// Reads stdin on its own thread, as blocking on it would stop us receiving.
+fn stdin() -> mpsc::Receiver<Vec<u8>> {
+    let (mut tx, rx) = mpsc::channel(1);
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        let mut buf = [0; 4096];
+        loop {
+            let len = match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            tx = match tx.send(buf[..len].to_vec()).wait() {
+                Ok(tx) => tx,
+                Err(_) => break,
+            };
+        }
+    });
+    rx
+}
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn run(listen: bool, addr: SocketAddr, host: HostId) -> io::Result<()> {
+    let mut core = Core::new()?;
+    let handle = core.handle();
+
+    let socket: Box<Future<Item=TcpStream, Error=io::Error>> = if listen {
+        let listener = TcpListener::bind(&addr, &handle)?;
+        eprintln!("listening on {}", listener.local_addr()?);
+        Box::new(listener.incoming().into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(socket, _)| socket.map(|(socket, _)| socket).ok_or_else(|| io::Error::new(io::ErrorKind::Other, "listener closed"))))
+    } else {
+        Box::new(TcpStream::connect(&addr, &handle))
+    };
+
+    let connect = socket.and_then(|socket| {
+        let parts = FramedParts { inner: socket, readbuf: BytesMut::new(), writebuf: BytesMut::new() };
+        secio::handshake(HandshakeConfig::new(), parts, host, PeerId::Unknown)
+    });
+    let (peer, stream) = core.run(connect)?;
+    eprintln!("connected to {:?}", peer);
+    eprintln!("curve: {}, cipher: {}, hash: {}", stream.curve(), stream.cipher(), stream.hash());
+
+    let (reader, writer) = stream.split();
+    let send = stdin()
+        .map_err(|()| io::Error::new(io::ErrorKind::Other, "stdin thread failed"))
+        .fold(writer, |writer, data| write_all(writer, data).and_then(|(writer, _)| flush(writer)))
+        .and_then(|mut writer| future::poll_fn(move || writer.shutdown()))
+        // Finishing sending only closes our half, the peer may still reply.
+        .and_then(|()| future::empty::<(), io::Error>());
+    let receive = copy(reader, Stdout(io::stdout())).map(|_| ());
+
+    core.run(receive.select(send).map(|_| ()).map_err(|(err, _)| err))
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let listen = args.first().map_or(false, |arg| arg == "-l");
+    if listen {
+        args.remove(0);
+    }
+    if args.len() != 3 {
+        eprintln!("usage: secio-cat [-l] <address> <private key> <public key>");
+        process::exit(2);
+    }
+
+    let result = args[0].parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid address: {}", err)))
+        .and_then(|addr| {
+            let host = HostId::from_der(read_file(&args[1])?, read_file(&args[2])?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("invalid key: {:?}", err)))?;
+            run(listen, addr, host)
+        });
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
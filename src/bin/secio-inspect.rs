@@ -0,0 +1,66 @@
+//! Inspect the handshake of a captured secio session.
+//!
+//! Usage: `secio-inspect <first> <second>`, where each file is a raw dump of
+//! everything one side of the connection sent, either as binary or hex.
+//!
+//! Prints the identities and negotiated algorithms, then the size of each
+//! frame sent after the handshake. This never decrypts anything: that needs
+//! the session keys, which libp2p-crypto has no way to export.
+
+extern crate libp2p_secio as secio;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+use std::str;
+
+use secio::inspect::{self, Session};
+
+fn unhex(contents: &[u8]) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = contents.iter().cloned().filter(|b| !b" \t\r\n".contains(b)).collect();
+    if digits.is_empty() || digits.len() % 2 != 0 || !digits.iter().all(|&b| (b as char).is_digit(16)) {
+        return None;
+    }
+    Some(digits.chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect())
+}
+
+fn load(path: &str) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(unhex(&contents).unwrap_or(contents))
+}
+
+fn print(session: &Session) {
+    println!("side 0: {:?}", session.peers[0]);
+    println!("side 1: {:?}", session.peers[1]);
+    println!("curve: {}, cipher: {}, hash: {}", session.curve, session.cipher, session.hash);
+    println!("typed records: {}", session.typed_records);
+    for side in 0..2 {
+        println!();
+        println!("side {} sent {} frames after the handshake:", side, session.frames[side].len());
+        for (i, frame) in session.frames[side].iter().enumerate() {
+            println!("  #{}: {} bytes, still encrypted", i, frame.len());
+        }
+    }
+}
+
+fn run(first: &str, second: &str) -> io::Result<()> {
+    let session = inspect::inspect(&load(first)?, &load(second)?)?;
+    print(&session);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <first> <second>", args[0]);
+        process::exit(2);
+    }
+    if let Err(err) = run(&args[1], &args[2]) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
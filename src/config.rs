@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+use extensions::Extensions;
+use metrics::MetricsRecorder;
+use observer::HandshakeObserver;
+use rng::{OsRng, Rng};
+
+/// Options controlling the secio handshake and the resulting `SecStream`.
+#[derive(Clone, Debug)]
+pub struct HandshakeConfig {
+    pub(crate) curves: Vec<CurveAlgorithm>,
+    pub(crate) ciphers: Vec<CipherAlgorithm>,
+    pub(crate) hashes: Vec<HashAlgorithm>,
+    pub(crate) rekey: Option<RekeyLimits>,
+    pub(crate) extensions: Extensions,
+    pub(crate) rng: Arc<Mutex<Rng + Send>>,
+    pub(crate) observer: Option<Arc<HandshakeObserver + Send + Sync>>,
+    pub(crate) metrics: Option<Arc<MetricsRecorder + Send + Sync>>,
+    #[cfg(feature = "slog")]
+    pub(crate) logger: Option<::slog::Logger>,
+}
+
+/// Limits after which a `SecStream` will replace its keys via a fresh
+/// ephemeral exchange, whichever is reached first.
+///
+/// Limits are only checked when sending data, an idle stream will rekey
+/// before it next sends anything.
+///
+/// A rekey only completes once the peer's half of the exchange has been
+/// read, so a stream that is only ever written to starts a single rekey and
+/// then keeps using its current keys, ignoring the limits, until it is next
+/// read from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RekeyLimits {
+    /// Plaintext bytes sent since the keys were last changed.
+    pub bytes: Option<u64>,
+    /// Frames sent since the keys were last changed.
+    pub frames: Option<u64>,
+    /// Time elapsed since the keys were last changed.
+    pub interval: Option<Duration>,
+}
+
+impl HandshakeConfig {
+    pub fn new() -> HandshakeConfig {
+        HandshakeConfig::default()
+    }
+
+    /// The key exchange curves to offer, in order of preference.
+    pub fn curves(mut self, curves: Vec<CurveAlgorithm>) -> HandshakeConfig {
+        self.curves = curves;
+        self
+    }
+
+    /// The ciphers to offer, in order of preference.
+    pub fn ciphers(mut self, ciphers: Vec<CipherAlgorithm>) -> HandshakeConfig {
+        self.ciphers = ciphers;
+        self
+    }
+
+    /// The MAC hashes to offer, in order of preference.
+    pub fn hashes(mut self, hashes: Vec<HashAlgorithm>) -> HandshakeConfig {
+        self.hashes = hashes;
+        self
+    }
+
+    /// The source of randomness for our nonce, shared by clones of this
+    /// config.
+    ///
+    /// Ephemeral keys are still generated by libp2p-crypto from the
+    /// operating system's random number generator, so with a seeded `rng`
+    /// our proposal is reproducible but the exchange, and everything after
+    /// it, is not.
+    pub fn rng<R: Rng + Send + 'static>(mut self, rng: R) -> HandshakeConfig {
+        self.rng = Arc::new(Mutex::new(rng));
+        self
+    }
+
+    /// Notify `observer` of the progress of every handshake using this
+    /// config, or clones of it.
+    pub fn observer<O: HandshakeObserver + Send + Sync + 'static>(mut self, observer: O) -> HandshakeConfig {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Record metrics for every handshake using this config, or clones of
+    /// it, and the streams they produce.
+    ///
+    /// No metrics are collected unless a recorder is given.
+    pub fn metrics<M: MetricsRecorder + Send + Sync + 'static>(mut self, recorder: M) -> HandshakeConfig {
+        self.metrics = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Log the progress of handshakes using this config, and any errors in
+    /// the streams they produce, to `logger`.
+    #[cfg(feature = "slog")]
+    pub fn logger(mut self, logger: ::slog::Logger) -> HandshakeConfig {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Rekey the stream whenever any of `limits` is reached.
+    ///
+    /// This only takes effect if the remote peer also supports rekeying,
+    /// with other peers the keys from the handshake are used for the
+    /// lifetime of the stream.
+    pub fn rekey(mut self, limits: RekeyLimits) -> HandshakeConfig {
+        self.rekey = Some(limits);
+        self
+    }
+
+    /// Advertise no protocol extensions, behaving like go-libp2p and
+    /// js-libp2p peers do.
+    #[cfg(feature = "testing")]
+    #[doc(hidden)]
+    pub fn legacy(mut self) -> HandshakeConfig {
+        self.extensions = Extensions::default();
+        self
+    }
+}
+
+impl Default for HandshakeConfig {
+    /// Offers only the first supported curve, cipher and hash, as the
+    /// handshake always has, and never rekeys.
+    fn default() -> HandshakeConfig {
+        HandshakeConfig {
+            curves: vec![CurveAlgorithm::all()[0]],
+            ciphers: vec![CipherAlgorithm::all()[0]],
+            hashes: vec![HashAlgorithm::all()[0]],
+            rekey: None,
+            extensions: Extensions::supported(),
+            rng: Arc::new(Mutex::new(OsRng)),
+            observer: None,
+            metrics: None,
+            #[cfg(feature = "slog")]
+            logger: None,
+        }
+    }
+}
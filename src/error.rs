@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a handshake failed, reported as the `kind` label of
+/// `secio_handshakes_failed_total`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Failure {
+    /// The peer closed the connection partway through.
+    Eof,
+    /// A message was oversized or couldn't be parsed.
+    Malformed,
+    /// The peer's public key wasn't that of the peer we expected.
+    PeerMismatch,
+    /// We connected to ourselves.
+    SelfConnection,
+    NoCommonAlgorithms,
+    /// The peer's exchange wasn't signed by its identity.
+    BadSignature,
+    /// A frame failed MAC verification.
+    Mac,
+    /// The peer echoed back something other than our nonce.
+    NonceMismatch,
+    /// Anything else, mostly errors from the transport.
+    Io,
+}
+
+/// The error carried inside an `io::Error` so the failure can be recovered
+/// from it.
+#[derive(Debug)]
+struct Failed {
+    failure: Failure,
+    msg: String,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl Error for Failed {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+/// An error with the given message that is classified as `failure`.
+pub(crate) fn failed<M: Into<String>>(failure: Failure, msg: M) -> io::Error {
+    failed_with(io::ErrorKind::Other, failure, msg)
+}
+
+/// As `failed`, but keeping the `kind` of the error it stands in for.
+pub(crate) fn failed_with<M: Into<String>>(kind: io::ErrorKind, failure: Failure, msg: M) -> io::Error {
+    io::Error::new(kind, Failed { failure, msg: msg.into() })
+}
+
+impl Failure {
+    pub(crate) fn of(err: &io::Error) -> Failure {
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<Failed>()) {
+            Some(failed) => failed.failure,
+            None if err.kind() == io::ErrorKind::UnexpectedEof => Failure::Eof,
+            None => Failure::Io,
+        }
+    }
+
+    /// A stable name for use as a metric label.
+    pub(crate) fn label(&self) -> &'static str {
+        match *self {
+            Failure::Eof => "eof",
+            Failure::Malformed => "malformed",
+            Failure::PeerMismatch => "peer_mismatch",
+            Failure::SelfConnection => "self_connection",
+            Failure::NoCommonAlgorithms => "no_common_algorithms",
+            Failure::BadSignature => "bad_signature",
+            Failure::Mac => "mac",
+            Failure::NonceMismatch => "nonce_mismatch",
+            Failure::Io => "io",
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::ops::BitAnd;
+
+/// Extensions to the secio protocol understood by this implementation.
+///
+/// These are advertised by appending marker entries to the comma separated
+/// exchanges list of our proposal. Implementations that don't know about
+/// them will never select them, so vanilla peers are unaffected, and as the
+/// proposals are covered by the exchange signatures the markers can't be
+/// stripped by an attacker.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Extensions(u8);
+
+const REKEY: u8 = 1 << 0;
+const CLOSE: u8 = 1 << 1;
+
+const MARKERS: &[(&str, u8)] = &[
+    ("x-secio-rekey", REKEY),
+    ("x-secio-close", CLOSE),
+];
+
+impl Extensions {
+    /// All the extensions we support.
+    pub(crate) fn supported() -> Extensions {
+        Extensions(REKEY | CLOSE)
+    }
+
+    /// The extensions advertised in a proposal's exchanges list.
+    pub(crate) fn from_list(list: &str) -> Extensions {
+        Extensions(list.split(',')
+            .filter_map(|entry| MARKERS.iter().find(|&&(marker, _)| marker == entry))
+            .fold(0, |bits, &(_, bit)| bits | bit))
+    }
+
+    /// Append the markers for these extensions to an exchanges list.
+    pub(crate) fn append_to(&self, list: &mut String) {
+        for &(marker, bit) in MARKERS {
+            if self.0 & bit != 0 {
+                list.push(',');
+                list.push_str(marker);
+            }
+        }
+    }
+
+    /// Whether frames carry a record type, required by any extension that
+    /// needs to send control records.
+    pub(crate) fn typed_records(&self) -> bool {
+        self.0 != 0
+    }
+
+    pub(crate) fn rekey(&self) -> bool {
+        self.0 & REKEY != 0
+    }
+
+    /// Whether a close record is sent on shutdown, letting the reader tell a
+    /// graceful close from a truncated stream.
+    pub(crate) fn close(&self) -> bool {
+        self.0 & CLOSE != 0
+    }
+}
+
+impl BitAnd for Extensions {
+    type Output = Extensions;
+
+    fn bitand(self, other: Extensions) -> Extensions {
+        Extensions(self.0 & other.0)
+    }
+}
@@ -0,0 +1,88 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`, only available with
+//! the `fuzzing` feature.
+
+use std::cmp::Ordering;
+
+use bytes::BytesMut;
+use identity::PeerId;
+use protobuf::parse_from_bytes;
+use tokio_io::codec::Decoder;
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+use config::HandshakeConfig;
+use data::{Propose, Exchange};
+use extensions::Extensions;
+use metrics::Metrics;
+use handshake::select;
+use secstream::{RekeyParams, SecStreamCodec};
+use testing::alice;
+
+/// Parse a proposal as received from a peer and select algorithms from it
+/// with either ordering.
+pub fn propose(data: &[u8]) {
+    let proposal: Propose = match parse_from_bytes(data) {
+        Ok(proposal) => proposal,
+        Err(_) => return,
+    };
+    let config = HandshakeConfig::new();
+    let _ = PeerId::from_protobuf(proposal.get_pubkey());
+    let _ = Extensions::from_list(proposal.get_exchanges());
+    let _ = select(&config, &proposal, Ordering::Less);
+    let _ = select(&config, &proposal, Ordering::Greater);
+}
+
+/// Parse an exchange as received from a peer and verify its signature as
+/// though it came from `alice`.
+pub fn exchange(data: &[u8]) {
+    let exchange: Exchange = match parse_from_bytes(data) {
+        Ok(exchange) => exchange,
+        Err(_) => return,
+    };
+    let peer = PeerId::from_protobuf(&alice().pub_key().to_protobuf().unwrap()).unwrap();
+    let corpus = {
+        let mut corpus = BytesMut::new();
+        corpus.extend_from_slice(b"their proposal");
+        corpus.extend_from_slice(b"my proposal");
+        corpus.extend_from_slice(exchange.get_epubkey());
+        corpus
+    };
+    let _ = peer.verify(&corpus, exchange.get_signature());
+}
+
+/// Decode frames received after a handshake, with every extension enabled.
+///
+/// The input is a sequence of plaintext records, each preceded by a big
+/// endian `u16` length. They are encrypted as the peer would before being
+/// decoded, so every record passes the MAC check and reaches record parsing
+/// whatever ephemeral keys were generated. A length running past the end of
+/// the input marks the rest of it as raw bytes to append after the frames,
+/// exercising the framing itself.
+pub fn decode(data: &[u8]) {
+    let (curve, cipher, hash) = (CurveAlgorithm::all()[0], CipherAlgorithm::all()[0], HashAlgorithm::all()[0]);
+    let mut ours = curve.generate_priv_key().unwrap();
+    let mut theirs = curve.generate_priv_key().unwrap();
+    let our_pubkey = ours.pub_key().unwrap().to_owned();
+    let their_pubkey = theirs.pub_key().unwrap().to_owned();
+    let our_algos = ours.agree_with(&their_pubkey, hash, cipher, true).unwrap();
+    let their_algos = theirs.agree_with(&our_pubkey, hash, cipher, false).unwrap();
+    let params = |local_first| RekeyParams { curve, hash, cipher, local_first, limits: None };
+    let mut codec = SecStreamCodec::new(our_algos, Extensions::supported(), params(true), Metrics::default());
+    let mut peer = SecStreamCodec::new(their_algos, Extensions::supported(), params(false), Metrics::default());
+
+    let mut src = BytesMut::new();
+    let mut rest = data;
+    while rest.len() >= 2 {
+        let len = (rest[0] as usize) << 8 | rest[1] as usize;
+        rest = &rest[2..];
+        if len > rest.len() {
+            break;
+        }
+        peer.encrypt_msg(&rest[..len], &mut src).unwrap();
+        rest = &rest[len..];
+    }
+    src.extend_from_slice(rest);
+
+    while let Ok(Some(_)) = codec.decode(&mut src) {
+    }
+}
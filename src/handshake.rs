@@ -1,6 +1,7 @@
 use std::io;
 use std::cmp::Ordering;
 use std::iter::FromIterator;
+use std::time::Instant;
 
 use bytes::{Bytes, BytesMut};
 use futures::{Future, Stream, Sink};
@@ -9,169 +10,368 @@ use identity::{ HostId, PeerId };
 use mhash::MultiHash;
 use msgio;
 use protobuf::{ ProtobufError, Message, parse_from_bytes };
-use secstream::SecStream;
+use config::HandshakeConfig;
+use error::{Failure, failed};
+use extensions::Extensions;
+use log::Log;
+use metrics::Metrics;
+use observer::Progress;
+use rng::Rng;
+use secret::{self, Secret, SharedSecret};
+use secstream::{RekeyParams, SecStream};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::io::{flush, read_exact, write_all};
-use tokio_io::codec::{Framed, FramedParts};
-use slog::Logger;
+use tokio_io::codec::{Decoder, Encoder, Framed, FramedParts};
 
-use crypto::rand;
 use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
 use data::{ Propose, Exchange };
 
-const NONCE_SIZE: usize = 16;
+pub(crate) const NONCE_SIZE: usize = 16;
 
-fn pbetio(e: ProtobufError) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, e)
+/// The largest proposal or exchange we will buffer. They only hold a public
+/// key, a signature and a few short strings, so this is far more than any
+/// honest peer sends.
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Length prefixed framing for the handshake messages, refusing oversized
+/// lengths up front rather than waiting for data that may never come.
+struct HandshakeCodec(msgio::LengthPrefixed);
+
+impl Decoder for HandshakeCodec {
+    type Item = <msgio::LengthPrefixed as Decoder>::Item;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() >= 4 {
+            let len = (src[0] as usize) << 24 | (src[1] as usize) << 16 | (src[2] as usize) << 8 | src[3] as usize;
+            if len > MAX_MESSAGE_LEN {
+                return Err(failed(Failure::Malformed, format!("handshake message of {} bytes exceeds maximum of {}", len, MAX_MESSAGE_LEN)));
+            }
+        }
+        Ok(self.0.decode(src)?)
+    }
 }
 
-fn select(proposal: &Propose, _order: Ordering) -> io::Result<(CurveAlgorithm, CipherAlgorithm, HashAlgorithm)> {
-    // TODO: actually select the algorithms
-    if !proposal.get_exchanges().split(',').any(|s| s == CurveAlgorithm::all()[0].to_string()) {
-        return Err(io::Error::new(io::ErrorKind::Other, "couldn't select a common exchange"));
+impl Encoder for HandshakeCodec {
+    type Item = <msgio::LengthPrefixed as Encoder>::Item;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        Ok(self.0.encode(item, dst)?)
     }
-    if !proposal.get_ciphers().split(',').any(|s| s == CipherAlgorithm::all()[0].to_string()) {
-        return Err(io::Error::new(io::ErrorKind::Other, "couldn't select a common cipher"));
+}
+
+pub(crate) fn pbetio(e: ProtobufError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Decides whose preferences win during selection, and which half of the
+/// stretched key material each side uses.
+pub(crate) fn order(my_pubkey: &[u8], my_nonce: &[u8], their_pubkey: &[u8], their_nonce: &[u8]) -> Ordering {
+    let order1 = MultiHash::generate_sha2_256(&Bytes::from(Vec::from_iter(their_pubkey.iter().chain(my_nonce).cloned())));
+    let order2 = MultiHash::generate_sha2_256(&Bytes::from(Vec::from_iter(my_pubkey.iter().chain(their_nonce).cloned())));
+    order1.to_bytes().cmp(&order2.to_bytes())
+}
+
+/// The data signed in an exchange, `first` being the signer's proposal.
+pub(crate) fn corpus(first: &[u8], second: &[u8], epubkey: &[u8]) -> BytesMut {
+    let mut corpus = BytesMut::new();
+    corpus.extend_from_slice(first);
+    corpus.extend_from_slice(second);
+    corpus.extend_from_slice(epubkey);
+    corpus
+}
+
+/// A hash of the handshake transcript, identical on both sides, that
+/// applications can sign to bind their own authentication to this session.
+///
+/// The proposals and exchanges are each length prefixed and arranged by the
+/// handshake ordering, the side whose keys come first leading.
+pub(crate) fn channel_binding(order: Ordering, my_proposal: &[u8], their_proposal: &[u8], my_exchange: &[u8], their_exchange: &[u8]) -> Bytes {
+    let parts = if order == Ordering::Less {
+        [my_proposal, their_proposal, my_exchange, their_exchange]
+    } else {
+        [their_proposal, my_proposal, their_exchange, my_exchange]
+    };
+    let mut transcript = BytesMut::new();
+    for part in &parts {
+        let len = part.len() as u32;
+        transcript.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        transcript.extend_from_slice(part);
     }
-    if !proposal.get_hashes().split(',').any(|s| s == HashAlgorithm::all()[0].to_string()) {
-        return Err(io::Error::new(io::ErrorKind::Other, "couldn't select a common hash"));
+    Bytes::from(MultiHash::generate_sha2_256(&transcript.freeze()).to_bytes())
+}
+
+fn join<T: ToString>(algos: &[T]) -> String {
+    algos.iter().map(|algo| algo.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Pick the first algorithm in common, preferring whichever side's list the
+/// ordering favours, matching go-libp2p's `selectBest`.
+fn select_best<T: ToString + Copy>(order: Ordering, ours: &[T], theirs: &str, kind: &str) -> io::Result<T> {
+    let theirs: Vec<&str> = theirs.split(',').collect();
+    let selected = match order {
+        Ordering::Greater => {
+            ours.iter().find(|ours| theirs.iter().any(|theirs| *theirs == ours.to_string())).cloned()
+        }
+        _ => {
+            theirs.iter().filter_map(|theirs| ours.iter().find(|ours| *theirs == ours.to_string())).next().cloned()
+        }
+    };
+    selected.ok_or_else(|| failed(Failure::NoCommonAlgorithms, format!("couldn't select a common {}", kind)))
+}
+
+#[cfg(feature = "tools")]
+fn known<T: ToString + Copy>(all: &[T], list: &str) -> Vec<T> {
+    list.split(',').filter_map(|name| all.iter().find(|algo| algo.to_string() == name).cloned()).collect()
+}
+
+/// The config that would have produced the algorithms offered in a
+/// proposal, ignoring any we don't know of.
+#[cfg(feature = "tools")]
+pub(crate) fn offered(proposal: &Propose) -> HandshakeConfig {
+    HandshakeConfig::new()
+        .curves(known(&CurveAlgorithm::all()[..], proposal.get_exchanges()))
+        .ciphers(known(&CipherAlgorithm::all()[..], proposal.get_ciphers()))
+        .hashes(known(&HashAlgorithm::all()[..], proposal.get_hashes()))
+}
+
+pub(crate) fn select(config: &HandshakeConfig, proposal: &Propose, order: Ordering) -> io::Result<(CurveAlgorithm, CipherAlgorithm, HashAlgorithm)> {
+    let curve = select_best(order, &config.curves, proposal.get_exchanges(), "exchange")?;
+    let cipher = select_best(order, &config.ciphers, proposal.get_ciphers(), "cipher")?;
+    let hash = select_best(order, &config.hashes, proposal.get_hashes(), "hash")?;
+    Ok((curve, cipher, hash))
+}
+
+#[async]
+pub fn handshake<S: AsyncRead + AsyncWrite + 'static>(config: HandshakeConfig, transport: FramedParts<S>, host: HostId, peer: PeerId) -> io::Result<(PeerId, SecStream<S>)> {
+    let log = Log::handshake(&config);
+    let progress = Progress::start(config.observer.clone());
+    let metrics = Metrics::new(config.metrics.clone());
+    let start = Instant::now();
+    metrics.increment("secio_handshakes_started_total", &[], 1);
+    match await!(run(log.clone(), config, transport, host, peer, progress.clone(), metrics.clone())) {
+        Ok((peer, secstream)) => {
+            progress.notify(|observer, elapsed| observer.finished(&peer, elapsed));
+            metrics.increment("secio_handshakes_succeeded_total", &[], 1);
+            metrics.observe_duration("secio_handshake_duration_seconds", &[], start.elapsed());
+            metrics.increment("secio_negotiated_suites_total", &[
+                ("curve", &secstream.curve().to_string()),
+                ("cipher", &secstream.cipher().to_string()),
+                ("hash", &secstream.hash().to_string()),
+            ], 1);
+            Ok((peer, secstream))
+        }
+        Err(err) => {
+            log!(info, log, "Secure handshake failed"; error = err);
+            progress.notify(|observer, elapsed| observer.failed(&err, elapsed));
+            metrics.increment("secio_handshakes_failed_total", &[("kind", Failure::of(&err).label())], 1);
+            Err(err)
+        }
     }
-    Ok((CurveAlgorithm::all()[0], CipherAlgorithm::all()[0], HashAlgorithm::all()[0])) // TODO: Return actual (exchange,cipher,hash)
 }
 
 #[async]
-pub fn handshake<S: AsyncRead + AsyncWrite + 'static>(logger: Logger, transport: FramedParts<S>, host: HostId, peer: PeerId) -> io::Result<(PeerId, SecStream<S>)> {
-    let transport = Framed::from_parts(transport, msgio::LengthPrefixed(msgio::Prefix::BigEndianU32, msgio::Suffix::None));
+fn run<S: AsyncRead + AsyncWrite + 'static>(log: Log, config: HandshakeConfig, transport: FramedParts<S>, host: HostId, peer: PeerId, progress: Progress, metrics: Metrics) -> io::Result<(PeerId, SecStream<S>)> {
+    let transport = Framed::from_parts(transport, HandshakeCodec(msgio::LengthPrefixed(msgio::Prefix::BigEndianU32, msgio::Suffix::None)));
 
     // step 1. Propose -- propose cipher suite + send pubkeys + nonce
-    info!(logger, "secure handshake start");
+    log!(info, log, "secure handshake start");
 
     let my_nonce = {
-        let mut nonce = [0; NONCE_SIZE];
-        rand::fill(&mut nonce)?;
+        let mut nonce = Secret([0; NONCE_SIZE]);
+        let mut rng = config.rng.lock().map_err(|_| io::Error::new(io::ErrorKind::Other, "handshake rng was poisoned"))?;
+        rng.fill(&mut *nonce)?;
         nonce
     };
 
-    let my_proposal = {
+    let mut my_proposal = {
         let mut proposal = Propose::new();
         proposal.set_rand(my_nonce.as_ref().to_owned());
         proposal.set_pubkey(host.pub_key().to_protobuf()?);
-        proposal.set_exchanges(CurveAlgorithm::all()[0].to_string());
-        proposal.set_ciphers(CipherAlgorithm::all()[0].to_string());
-        proposal.set_hashes(HashAlgorithm::all()[0].to_string());
+        let mut exchanges = join(&config.curves);
+        config.extensions.append_to(&mut exchanges);
+        proposal.set_exchanges(exchanges);
+        proposal.set_ciphers(join(&config.ciphers));
+        proposal.set_hashes(join(&config.hashes));
         proposal
     };
 
-    info!(logger, "Sending proposal";
-        "curves" => my_proposal.get_exchanges(),
-        "ciphers" => my_proposal.get_ciphers(),
-        "hashes" => my_proposal.get_hashes());
+    log!(info, log, "Sending proposal";
+        curves = my_proposal.get_exchanges(),
+        ciphers = my_proposal.get_ciphers(),
+        hashes = my_proposal.get_hashes());
 
-    let my_proposal_bytes = Bytes::from(my_proposal.write_to_bytes().map_err(pbetio)?);
+    // The encoded proposal holds our nonce as well, so it's wiped too.
+    let my_proposal_bytes = SharedSecret(Bytes::from(my_proposal.write_to_bytes().map_err(pbetio)?));
+    secret::zero(my_proposal.mut_rand());
 
     let transport = await!(transport.send(my_proposal_bytes.clone()))?;
+    progress.notify(|observer, elapsed| observer.proposal_sent(elapsed));
 
     let (their_proposal_bytes, transport) = await!(transport.into_future().map_err(|(err, _)| err))?;
     let their_proposal_bytes = match their_proposal_bytes {
         Some(bytes) => bytes,
         None => {
-            return Err(io::Error::new(io::ErrorKind::Other, "Unexpected EOF"));
+            return Err(failed(Failure::Eof, "Unexpected EOF"));
         }
     };
 
-    let mut their_proposal: Propose = parse_from_bytes(&their_proposal_bytes).map_err(pbetio)?;
-    info!(logger, "Received proposal";
-          "curves" => their_proposal.get_exchanges(),
-          "ciphers" => their_proposal.get_ciphers(),
-          "hashes" => their_proposal.get_hashes());
+    let mut their_proposal: Propose = parse_from_bytes(&their_proposal_bytes)
+        .map_err(|e| failed(Failure::Malformed, format!("invalid proposal: {}", e)))?;
+    log!(info, log, "Received proposal";
+          curves = their_proposal.get_exchanges(),
+          ciphers = their_proposal.get_ciphers(),
+          hashes = their_proposal.get_hashes());
+    progress.notify(|observer, elapsed| observer.proposal_received(elapsed));
 
     // // step 1.1 Identify -- get identity from their key
     let peer = {
         let actual_id = PeerId::from_protobuf(&their_proposal.get_pubkey())?;
         if let PeerId::Unknown = peer { /* ok */ } else {
             if !actual_id.matches(&peer) {
-                return Err(io::Error::new(io::ErrorKind::Other, format!("public key from actual peer {:?} didn't match provided id {:?}", actual_id, peer)));
+                return Err(failed(Failure::PeerMismatch, format!("public key from actual peer {:?} didn't match provided id {:?}", actual_id, peer)));
             }
         }
         actual_id
     };
-    info!(logger, "identified peer"; "peer" => ?peer);
+    log!(info, log, "identified peer"; peer = peer);
+    log.record_peer(&peer);
+    progress.notify(|observer, elapsed| observer.peer_identified(&peer, elapsed));
 
-    let order = {
-        let order1 = MultiHash::generate_sha2_256(&Bytes::from(Vec::from_iter(their_proposal.get_pubkey().iter().chain(my_nonce.iter()).cloned())));
-        let order2 = MultiHash::generate_sha2_256(&Bytes::from(Vec::from_iter(my_proposal.get_pubkey().iter().chain(their_proposal.get_rand()).cloned())));
-        order1.to_bytes().cmp(&order2.to_bytes())
-    };
+    let order = order(my_proposal.get_pubkey(), &my_nonce[..], their_proposal.get_pubkey(), their_proposal.get_rand());
 
     if order == Ordering::Equal {
-        return Err(io::Error::new(io::ErrorKind::Other, "talking to self (same socket. must be reuseport + dialing self)"));
+        return Err(failed(Failure::SelfConnection, "talking to self (same socket. must be reuseport + dialing self)"));
     }
 
     // step 1.2 Selection -- select/agree on best encryption parameters
-    let (curve, cipher, hash) = select(&their_proposal, order)?;
-    info!(logger, "Selected"; "curve" => ?curve, "cipher" => ?cipher, "hash" => ?hash);
+    let (curve, cipher, hash) = select(&config, &their_proposal, order)?;
+    log!(info, log, "Selected"; curve = curve, cipher = cipher, hash = hash);
+    log.record_suite(curve, cipher, hash);
+    progress.notify(|observer, elapsed| observer.algorithms_selected(curve, cipher, hash, elapsed));
+
+    let extensions = config.extensions & Extensions::from_list(their_proposal.get_exchanges());
+    log!(info, log, "Negotiated extensions"; extensions = extensions);
 
     // step 2. Exchange -- exchange (signed) ephemeral keys. verify signatures.
     let mut my_ephemeral_priv_key = curve.generate_priv_key()?;
 
     // Gather corpus to sign.
-    let my_corpus = {
-        let mut corpus = BytesMut::new();
-        corpus.extend_from_slice(&my_proposal_bytes);
-        corpus.extend_from_slice(&their_proposal_bytes);
-        corpus.extend_from_slice(my_ephemeral_priv_key.pub_key()?);
-        corpus.freeze()
-    };
+    let my_corpus = Secret(corpus(&my_proposal_bytes, &their_proposal_bytes, my_ephemeral_priv_key.pub_key()?));
 
     let my_exchange = {
         let mut exchange = Exchange::new();
         exchange.set_epubkey(my_ephemeral_priv_key.pub_key()?.to_owned());
-        exchange.set_signature(host.sign(&my_corpus)?);
+        exchange.set_signature(host.sign(&my_corpus[..])?);
         exchange
     };
 
-    info!(logger, "Sending exchange");
+    log!(info, log, "Sending exchange");
     let my_exchange_bytes = Bytes::from(my_exchange.write_to_bytes().map_err(pbetio)?);
 
-    let transport = await!(transport.send(my_exchange_bytes))?;
+    let transport = await!(transport.send(my_exchange_bytes.clone()))?;
 
     let (their_exchange_bytes, transport) = await!(transport.into_future().map_err(|(err, _)| err))?;
     let their_exchange_bytes = match their_exchange_bytes {
         Some(bytes) => bytes,
         None => {
-            return Err(io::Error::new(io::ErrorKind::Other, "Unexpected EOF"));
+            return Err(failed(Failure::Eof, "Unexpected EOF"));
         }
     };
 
-    let their_exchange: Exchange = parse_from_bytes(&their_exchange_bytes).map_err(pbetio)?;
-    info!(logger, "Received exchange");
+    let their_exchange: Exchange = parse_from_bytes(&their_exchange_bytes)
+        .map_err(|e| failed(Failure::Malformed, format!("invalid exchange: {}", e)))?;
+    log!(info, log, "Received exchange");
 
     // step 2.1. Verify -- verify their exchange packet is good.
-    let their_corpus = {
-        let mut corpus = BytesMut::new();
-        corpus.extend_from_slice(&their_proposal_bytes);
-        corpus.extend_from_slice(&my_proposal_bytes);
-        corpus.extend_from_slice(&their_exchange.get_epubkey());
-        corpus
-    };
+    let their_corpus = Secret(corpus(&their_proposal_bytes, &my_proposal_bytes, their_exchange.get_epubkey()));
 
-    try!(peer.verify(&their_corpus, their_exchange.get_signature()));
-    info!(logger, "Verified exchange");
+    try!(peer.verify(&their_corpus[..], their_exchange.get_signature()).map_err(|_| failed(Failure::BadSignature, "exchange signature verification failed")));
+    log!(info, log, "Verified exchange");
+    progress.notify(|observer, elapsed| observer.exchange_verified(elapsed));
 
     // step 2.2. Keys -- generate keys for mac + encryption
     let algos = my_ephemeral_priv_key.agree_with(their_exchange.get_epubkey(), hash, cipher, order == Ordering::Less)?;
+    // Don't keep the ephemeral key alive in the generator any longer than
+    // needed. It isn't wiped: libp2p-crypto's key types, like the keys held
+    // by `algos`, don't zero themselves when dropped.
+    drop(my_ephemeral_priv_key);
 
     // step 3. Finish -- send expected message to verify encryption works (send local nonce)
     let parts = transport.into_parts();
-    let secstream = SecStream::new(logger.clone(), parts, algos);
-    let nonce = their_proposal.take_rand();
+    let binding = channel_binding(order, &my_proposal_bytes, &their_proposal_bytes, &my_exchange_bytes, &their_exchange_bytes);
+    let rekey = RekeyParams {
+        curve, hash, cipher,
+        local_first: order == Ordering::Less,
+        limits: config.rekey,
+    };
+    let secstream = SecStream::new(log.clone(), parts, algos, extensions, rekey, binding, metrics);
+    let nonce = Secret(their_proposal.take_rand());
     let (secstream, _) = await!(write_all(secstream, nonce))?;
     let secstream = await!(flush(secstream))?;
-    let (secstream, bytes) = await!(read_exact(secstream, [0; NONCE_SIZE]))?;
-    if my_nonce[..] != bytes[..] {
-        info!(logger, "my nonce {:?}, they gave {:?}", my_nonce, bytes);
-        return Err(io::Error::new(io::ErrorKind::Other, "Nonces did not match"));
+    let (secstream, bytes) = await!(read_exact(secstream, Secret([0; NONCE_SIZE])))?;
+    if !secret::constant_time_eq(&my_nonce[..], &bytes[..]) {
+        log!(info, log, "Peer did not echo our nonce");
+        return Err(failed(Failure::NonceMismatch, "Nonces did not match"));
     }
 
     return Ok((peer.clone(), secstream));
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+    use config::HandshakeConfig;
+    use data::Propose;
+    use error::Failure;
+    use super::{join, select, select_best};
+
+    #[test]
+    fn select_best_prefers_the_favoured_side() {
+        let ours = ["a", "b", "c"];
+        assert_eq!(select_best(Ordering::Greater, &ours, "c,b", "cipher").unwrap(), "b");
+        assert_eq!(select_best(Ordering::Less, &ours, "c,b", "cipher").unwrap(), "c");
+    }
+
+    #[test]
+    fn select_best_ignores_unknown_names() {
+        let ours = ["a", "b"];
+        assert_eq!(select_best(Ordering::Less, &ours, "x-unknown,b", "cipher").unwrap(), "b");
+        assert_eq!(select_best(Ordering::Greater, &ours, "x-unknown,b", "cipher").unwrap(), "b");
+    }
+
+    #[test]
+    fn select_best_without_a_common_algorithm() {
+        let err = select_best(Ordering::Less, &["a"], "b,c", "cipher").unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::NoCommonAlgorithms);
+        assert!(err.to_string().contains("common cipher"), "{:?}", err);
+    }
+
+    #[test]
+    fn default_offers_the_first_of_each() {
+        let config = HandshakeConfig::default();
+        assert_eq!(join(&config.curves), CurveAlgorithm::all()[0].to_string());
+        assert_eq!(join(&config.ciphers), CipherAlgorithm::all()[0].to_string());
+        assert_eq!(join(&config.hashes), HashAlgorithm::all()[0].to_string());
+    }
+
+    #[test]
+    fn default_selects_the_first_of_each() {
+        // Whatever a peer offers, and whichever side is favoured, the
+        // default config negotiates what the handshake always has.
+        let mut proposal = Propose::new();
+        proposal.set_exchanges(join(&CurveAlgorithm::all().iter().rev().cloned().collect::<Vec<_>>()));
+        proposal.set_ciphers(join(&CipherAlgorithm::all().iter().rev().cloned().collect::<Vec<_>>()));
+        proposal.set_hashes(join(&HashAlgorithm::all().iter().rev().cloned().collect::<Vec<_>>()));
+        for &order in &[Ordering::Less, Ordering::Greater] {
+            let (curve, cipher, hash) = select(&HandshakeConfig::default(), &proposal, order).unwrap();
+            assert_eq!(curve.to_string(), CurveAlgorithm::all()[0].to_string());
+            assert_eq!(cipher.to_string(), CipherAlgorithm::all()[0].to_string());
+            assert_eq!(hash.to_string(), HashAlgorithm::all()[0].to_string());
+        }
+    }
+}
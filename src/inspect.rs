@@ -0,0 +1,100 @@
+//! Decoding of the handshake in captured secio sessions for the
+//! `secio-inspect` tool, only available with the `tools` feature.
+
+use std::cmp::Ordering;
+use std::io;
+
+use bytes::Bytes;
+use identity::PeerId;
+use protobuf::parse_from_bytes;
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+use data::{Propose, Exchange};
+use extensions::Extensions;
+use handshake::{corpus, offered, order, select};
+
+/// What can be recovered from a capture of both directions of a session.
+#[derive(Debug)]
+pub struct Session {
+    /// The identity of each side, in the order the captures were given.
+    pub peers: [PeerId; 2],
+    pub curve: CurveAlgorithm,
+    pub cipher: CipherAlgorithm,
+    pub hash: HashAlgorithm,
+    /// Whether control records were negotiated, changing the plaintext
+    /// layout of every frame.
+    pub typed_records: bool,
+    /// The still encrypted frames sent by each side after the handshake,
+    /// without their length prefix.
+    pub frames: [Vec<Bytes>; 2],
+}
+
+fn other(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Split a capture of one direction into its length prefixed messages.
+fn messages(mut capture: &[u8]) -> io::Result<Vec<Bytes>> {
+    let mut messages = Vec::new();
+    while !capture.is_empty() {
+        if capture.len() < 4 {
+            return Err(other(format!("capture ends with {} bytes of a length prefix", capture.len())));
+        }
+        let len = (capture[0] as usize) << 24 | (capture[1] as usize) << 16 | (capture[2] as usize) << 8 | capture[3] as usize;
+        if capture.len() - 4 < len {
+            return Err(other(format!("message #{} claims {} bytes but only {} were captured", messages.len(), len, capture.len() - 4)));
+        }
+        messages.push(Bytes::from(&capture[4..4 + len]));
+        capture = &capture[4 + len..];
+    }
+    Ok(messages)
+}
+
+fn handshake_message(messages: &[Bytes], index: usize, side: usize) -> io::Result<&Bytes> {
+    messages.get(index).ok_or_else(|| other(format!("capture {} ends before the handshake does", side)))
+}
+
+/// Parse the handshake out of captures of each direction of a session,
+/// checking both exchange signatures and redoing algorithm selection.
+pub fn inspect(first: &[u8], second: &[u8]) -> io::Result<Session> {
+    let mut captures = [messages(first)?, messages(second)?];
+
+    let mut proposals = Vec::new();
+    let mut peers = Vec::new();
+    for side in 0..2 {
+        let bytes = handshake_message(&captures[side], 0, side)?;
+        let proposal: Propose = parse_from_bytes(bytes)
+            .map_err(|e| other(format!("invalid proposal from side {}: {}", side, e)))?;
+        peers.push(PeerId::from_protobuf(proposal.get_pubkey())?);
+        proposals.push(proposal);
+    }
+
+    for side in 0..2 {
+        let bytes = handshake_message(&captures[side], 1, side)?;
+        let exchange: Exchange = parse_from_bytes(bytes)
+            .map_err(|e| other(format!("invalid exchange from side {}: {}", side, e)))?;
+        let signed = corpus(&captures[side][0], &captures[1 - side][0], exchange.get_epubkey());
+        peers[side].verify(&signed, exchange.get_signature())
+            .map_err(|_| other(format!("exchange signature from side {} did not verify", side)))?;
+    }
+
+    let order = order(proposals[0].get_pubkey(), proposals[0].get_rand(), proposals[1].get_pubkey(), proposals[1].get_rand());
+    if order == Ordering::Equal {
+        return Err(other("both sides sent the same proposal".to_owned()));
+    }
+    let (curve, cipher, hash) = select(&offered(&proposals[0]), &proposals[1], order)?;
+    let extensions = Extensions::from_list(proposals[0].get_exchanges()) & Extensions::from_list(proposals[1].get_exchanges());
+
+    let second_frames = captures[1].split_off(2);
+    let first_frames = captures[0].split_off(2);
+    let second_peer = peers.pop().unwrap();
+    let first_peer = peers.pop().unwrap();
+
+    Ok(Session {
+        peers: [first_peer, second_peer],
+        curve, cipher, hash,
+        typed_records: extensions.typed_records(),
+        frames: [first_frames, second_frames],
+    })
+}
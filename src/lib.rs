@@ -3,18 +3,49 @@
 
 extern crate bytes;
 extern crate futures_await as futures;
+extern crate iovec;
 extern crate libp2p_crypto as crypto;
 extern crate libp2p_identity as identity;
 extern crate mhash;
 extern crate msgio;
 extern crate protobuf;
 extern crate tokio_io;
+#[cfg(test)]
+extern crate tokio_core;
+#[cfg(feature = "slog")]
 #[macro_use]
 extern crate slog;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
+#[macro_use]
+mod log;
+
+mod config;
 mod data;
+mod error;
+mod extensions;
 mod handshake;
+mod metrics;
+mod observer;
+mod rng;
+mod secret;
 mod secstream;
 
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(feature = "tools")]
+#[doc(hidden)]
+pub mod inspect;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+
+pub use config::{HandshakeConfig, RekeyLimits};
 pub use handshake::handshake;
-pub use secstream::SecStream;
+pub use metrics::{MetricsRecorder, PrometheusRecorder};
+pub use observer::HandshakeObserver;
+pub use rng::{OsRng, Rng};
+pub use secstream::{SecStream, Stats};
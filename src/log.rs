@@ -0,0 +1,81 @@
+//! Logging through whichever of the `slog` and `tracing` features are
+//! enabled, or neither.
+
+#[cfg(feature = "tracing")]
+use tracing::{Span, field};
+
+use identity::PeerId;
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+use config::HandshakeConfig;
+
+/// Where a handshake, and the stream it produces, send their logs.
+#[derive(Clone, Debug)]
+pub(crate) struct Log {
+    #[cfg(feature = "slog")]
+    pub(crate) slog: Option<::slog::Logger>,
+    #[cfg(feature = "tracing")]
+    pub(crate) span: Span,
+}
+
+impl Log {
+    #[cfg_attr(not(feature = "slog"), allow(unused_variables))]
+    pub(crate) fn handshake(config: &HandshakeConfig) -> Log {
+        Log {
+            #[cfg(feature = "slog")]
+            slog: config.logger.clone(),
+            #[cfg(feature = "tracing")]
+            span: ::tracing::info_span!("secio_handshake", peer = field::Empty, curve = field::Empty, cipher = field::Empty, hash = field::Empty),
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn record_peer(&self, peer: &PeerId) {
+        #[cfg(feature = "tracing")]
+        self.span.record("peer", &field::debug(peer));
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn record_suite(&self, curve: CurveAlgorithm, cipher: CipherAlgorithm, hash: HashAlgorithm) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("curve", &field::display(curve));
+            self.span.record("cipher", &field::display(cipher));
+            self.span.record("hash", &field::display(hash));
+        }
+    }
+}
+
+impl Default for Log {
+    /// Logs nowhere.
+    fn default() -> Log {
+        Log {
+            #[cfg(feature = "slog")]
+            slog: None,
+            #[cfg(feature = "tracing")]
+            span: Span::none(),
+        }
+    }
+}
+
+/// Log at `$level` (`debug`, `info` or `warn`) to a `Log`, any fields are
+/// recorded with their `Debug` implementation.
+macro_rules! log {
+    ($level:ident, $log:expr, $msg:tt) => {
+        log!($level, $log, $msg;)
+    };
+    ($level:ident, $log:expr, $msg:tt; $($key:ident = $value:expr),*) => {{
+        let _log: &::log::Log = &$log;
+        #[cfg(feature = "slog")]
+        {
+            if let Some(ref logger) = _log.slog {
+                $level!(logger, $msg; $(stringify!($key) => ?$value),*);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        {
+            _log.span.in_scope(|| ::tracing::$level!($($key = ?$value,)* $msg));
+        }
+    }};
+}
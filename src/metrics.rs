@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Receives counters and histogram observations from handshakes and
+/// secure streams.
+///
+/// Metric names follow Prometheus conventions, counters are suffixed with
+/// `_total` and durations are measured in seconds:
+///
+/// * `secio_handshakes_started_total`
+/// * `secio_handshakes_succeeded_total`
+/// * `secio_handshakes_failed_total`, labelled with the `kind` of failure:
+///   `eof`, `malformed`, `peer_mismatch`, `self_connection`,
+///   `no_common_algorithms`, `bad_signature`, `mac`, `nonce_mismatch` or
+///   `io` for anything else
+/// * `secio_handshake_duration_seconds`, a histogram of completed handshakes
+/// * `secio_negotiated_suites_total`, labelled with `curve`, `cipher` and
+///   `hash`
+/// * `secio_frames_encrypted_total` and `secio_bytes_encrypted_total`
+/// * `secio_frames_decrypted_total` and `secio_bytes_decrypted_total`
+/// * `secio_mac_failures_total`
+///
+/// Byte counts are of application data, excluding record types and control
+/// records, the same as `Stats`. Frame counts include control records.
+pub trait MetricsRecorder: Debug {
+    /// Add `value` to a counter.
+    fn increment(&self, name: &'static str, labels: &[(&'static str, &str)], value: u64);
+
+    /// Record a single observation in a histogram.
+    fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64);
+}
+
+/// A handle to an optional recorder, cheap to clone into every stream.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metrics(Option<Arc<MetricsRecorder + Send + Sync>>);
+
+impl Metrics {
+    pub(crate) fn new(recorder: Option<Arc<MetricsRecorder + Send + Sync>>) -> Metrics {
+        Metrics(recorder)
+    }
+
+    pub(crate) fn increment(&self, name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+        if let Some(ref recorder) = self.0 {
+            recorder.increment(name, labels, value);
+        }
+    }
+
+    pub(crate) fn observe_duration(&self, name: &'static str, labels: &[(&'static str, &str)], duration: Duration) {
+        if let Some(ref recorder) = self.0 {
+            recorder.observe(name, labels, duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+        }
+    }
+}
+
+/// Upper bounds of the buckets used for every histogram, suited to
+/// durations in seconds.
+const BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug)]
+enum Value {
+    Counter(u64),
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+/// Keeps metrics in memory to be rendered in the Prometheus text exposition
+/// format, clones share the same metrics.
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusRecorder {
+    metrics: Arc<Mutex<BTreeMap<Key, Value>>>,
+}
+
+fn key(name: &'static str, labels: &[(&'static str, &str)]) -> Key {
+    (name, labels.iter().map(|&(label, value)| (label, value.to_owned())).collect())
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_labels(out: &mut String, labels: &[(&'static str, String)], le: Option<&str>) {
+    let mut labels: Vec<String> = labels.iter().map(|&(label, ref value)| format!("{}=\"{}\"", label, escape(value))).collect();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{}\"", le));
+    }
+    if !labels.is_empty() {
+        write!(out, "{{{}}}", labels.join(",")).unwrap();
+    }
+}
+
+impl PrometheusRecorder {
+    pub fn new() -> PrometheusRecorder {
+        PrometheusRecorder::default()
+    }
+
+    /// Render every metric recorded so far.
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().expect("metrics lock poisoned");
+        let mut out = String::new();
+        let mut previous = None;
+        for (&(name, ref labels), value) in metrics.iter() {
+            if previous != Some(name) {
+                let kind = match *value {
+                    Value::Counter(_) => "counter",
+                    Value::Histogram { .. } => "histogram",
+                };
+                writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+                previous = Some(name);
+            }
+            match *value {
+                Value::Counter(count) => {
+                    out.push_str(name);
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", count).unwrap();
+                }
+                Value::Histogram { ref buckets, sum, count } => {
+                    for (bound, bucket) in BUCKETS.iter().zip(buckets) {
+                        write!(out, "{}_bucket", name).unwrap();
+                        write_labels(&mut out, labels, Some(&bound.to_string()));
+                        writeln!(out, " {}", bucket).unwrap();
+                    }
+                    write!(out, "{}_bucket", name).unwrap();
+                    write_labels(&mut out, labels, Some("+Inf"));
+                    writeln!(out, " {}", count).unwrap();
+                    write!(out, "{}_sum", name).unwrap();
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", sum).unwrap();
+                    write!(out, "{}_count", name).unwrap();
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", count).unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+impl MetricsRecorder for PrometheusRecorder {
+    fn increment(&self, name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        if let Value::Counter(ref mut count) = *metrics.entry(key(name, labels)).or_insert(Value::Counter(0)) {
+            *count += value;
+        }
+    }
+
+    fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        let entry = metrics.entry(key(name, labels))
+            .or_insert_with(|| Value::Histogram { buckets: vec![0; BUCKETS.len()], sum: 0.0, count: 0 });
+        if let Value::Histogram { ref mut buckets, ref mut sum, ref mut count } = *entry {
+            // Prometheus buckets are cumulative.
+            for (bound, bucket) in BUCKETS.iter().zip(buckets.iter_mut()) {
+                if value <= *bound {
+                    *bucket += 1;
+                }
+            }
+            *sum += value;
+            *count += 1;
+        }
+    }
+}
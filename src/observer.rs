@@ -0,0 +1,56 @@
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use identity::PeerId;
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+/// Notified as a handshake progresses, each callback is given the time
+/// elapsed since the handshake started.
+///
+/// Every callback defaults to doing nothing, so implementations only need
+/// to provide the ones they care about. Callbacks are run inline with the
+/// handshake and shouldn't block.
+pub trait HandshakeObserver: Debug {
+    /// Our proposal has been sent.
+    fn proposal_sent(&self, _elapsed: Duration) {}
+
+    /// The peer's proposal has been received and parsed.
+    fn proposal_received(&self, _elapsed: Duration) {}
+
+    /// The peer's public key has been checked against the expected id.
+    fn peer_identified(&self, _peer: &PeerId, _elapsed: Duration) {}
+
+    /// Algorithms have been selected from both proposals.
+    fn algorithms_selected(&self, _curve: CurveAlgorithm, _cipher: CipherAlgorithm, _hash: HashAlgorithm, _elapsed: Duration) {}
+
+    /// The peer's signature over their ephemeral key has been verified.
+    fn exchange_verified(&self, _elapsed: Duration) {}
+
+    /// The peer echoed our nonce and the secure stream is ready to use.
+    fn finished(&self, _peer: &PeerId, _elapsed: Duration) {}
+
+    /// The handshake failed, no further callbacks will be made.
+    fn failed(&self, _error: &io::Error, _elapsed: Duration) {}
+}
+
+/// Tracks a single handshake's start time for its observer, if any.
+#[derive(Clone, Debug)]
+pub(crate) struct Progress {
+    observer: Option<Arc<HandshakeObserver + Send + Sync>>,
+    start: Instant,
+}
+
+impl Progress {
+    pub(crate) fn start(observer: Option<Arc<HandshakeObserver + Send + Sync>>) -> Progress {
+        Progress { observer, start: Instant::now() }
+    }
+
+    pub(crate) fn notify<F: FnOnce(&HandshakeObserver, Duration)>(&self, f: F) {
+        if let Some(ref observer) = self.observer {
+            f(&**observer, self.start.elapsed());
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+use std::io;
+
+use crypto::rand;
+
+/// A source of randomness for the handshake's nonce.
+///
+/// Ephemeral keys are generated by libp2p-crypto, which always uses the
+/// operating system's random number generator.
+pub trait Rng: Debug {
+    /// Fill `buf` with random bytes.
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// The operating system's random number generator, used unless another is
+/// configured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsRng;
+
+impl Rng for OsRng {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        Ok(rand::fill(buf)?)
+    }
+}
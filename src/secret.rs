@@ -0,0 +1,231 @@
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{self, Ordering};
+
+use bytes::Bytes;
+
+/// Overwrite `buf` with zeroes, using volatile writes so that it isn't
+/// optimised away even if the buffer is about to be freed.
+pub(crate) fn zero(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// Compare two buffers in time dependent only on their lengths, not on
+/// where they first differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    unsafe { ptr::read_volatile(&diff) == 0 }
+}
+
+/// Zero `buf` if this is the last reference to its storage, otherwise
+/// whoever holds the other references is responsible for it.
+pub(crate) fn zero_bytes(buf: Bytes) {
+    if let Ok(mut buf) = buf.try_mut() {
+        zero(&mut buf);
+    }
+}
+
+/// A buffer holding secret data, zeroed when dropped.
+pub(crate) struct Secret<T: AsMut<[u8]>>(pub(crate) T);
+
+impl<T: AsMut<[u8]>> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: AsMut<[u8]>> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: AsMut<[u8]> + AsRef<[u8]>> AsRef<[u8]> for Secret<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl<T: AsMut<[u8]>> AsMut<[u8]> for Secret<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> Drop for Secret<T> {
+    fn drop(&mut self) {
+        zero(self.0.as_mut());
+    }
+}
+
+impl<T: AsMut<[u8]>> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Shared secret data, zeroed when the last reference to it is dropped.
+///
+/// Clones handed elsewhere, e.g. to a transport to send, must be dropped
+/// before this is for the storage to be wiped.
+pub(crate) struct SharedSecret(pub(crate) Bytes);
+
+impl Deref for SharedSecret {
+    type Target = Bytes;
+
+    fn deref(&self) -> &Bytes {
+        &self.0
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        zero_bytes(mem::replace(&mut self.0, Bytes::new()));
+    }
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedSecret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::slice;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use super::{Secret, SharedSecret, constant_time_eq, zero, zero_bytes};
+
+    const MARKER: &[u8] = b"secio secret marker!";
+
+    static FREED_MARKER: AtomicBool = AtomicBool::new(false);
+
+    /// Notes whether any allocation still held `MARKER` when it was freed.
+    struct Checking;
+
+    unsafe impl GlobalAlloc for Checking {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let block = slice::from_raw_parts(ptr, layout.size());
+            if block.windows(MARKER.len()).any(|window| window == MARKER) {
+                FREED_MARKER.store(true, Ordering::SeqCst);
+            }
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Checking = Checking;
+
+    /// A buffer too big for `Bytes` to store inline, built without any
+    /// reallocation that would free a copy of the marker.
+    fn marked() -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MARKER.len() * 4);
+        for _ in 0..4 {
+            buf.extend_from_slice(MARKER);
+        }
+        buf
+    }
+
+    fn freed_marker<F: FnOnce()>(f: F) -> bool {
+        FREED_MARKER.store(false, Ordering::SeqCst);
+        f();
+        FREED_MARKER.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn zero_overwrites_everything() {
+        let mut buf = [0xff; 33];
+        zero(&mut buf);
+        assert!(buf.iter().all(|&byte| byte == 0));
+    }
+
+    // The allocator checks share a flag, so they run in a single test.
+    #[test]
+    fn freed_secrets_are_wiped() {
+        // Without wiping the marker is seen, so the checks below mean
+        // something.
+        assert!(freed_marker(|| drop(marked())));
+
+        assert!(!freed_marker(|| drop(Secret(marked()))));
+        assert!(!freed_marker(|| drop(Secret(marked().into_boxed_slice()))));
+        assert!(!freed_marker(|| zero_bytes(Bytes::from(marked()))));
+        assert!(!freed_marker(|| {
+            let secret = SharedSecret(Bytes::from(marked()));
+            drop(secret.clone());
+            drop(secret);
+        }));
+
+        // A shared buffer is left alone until its last reference is wiped.
+        assert!(!freed_marker(|| {
+            let first = Bytes::from(marked());
+            let second = first.clone();
+            zero_bytes(first);
+            assert_eq!(&second[..MARKER.len()], MARKER);
+            zero_bytes(second);
+        }));
+    }
+
+    #[test]
+    fn constant_time_eq_compares() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"nonce", b"nonce"));
+        assert!(!constant_time_eq(b"nonce", b"nonc"));
+        assert!(!constant_time_eq(b"", b"n"));
+        assert!(!constant_time_eq(b"nonce", b"Nonce"));
+        assert!(!constant_time_eq(b"nonce", b"noncE"));
+        assert!(!constant_time_eq(&[0; 16], &[0x80; 16]));
+    }
+
+    fn time_comparisons(a: &[u8], b: &[u8]) -> Duration {
+        // The fastest of several runs is the least disturbed by scheduling.
+        (0..10).map(|_| {
+            let start = Instant::now();
+            for _ in 0..100_000 {
+                assert!(!constant_time_eq(a, b));
+            }
+            start.elapsed()
+        }).min().unwrap()
+    }
+
+    fn nanos(duration: Duration) -> f64 {
+        duration.as_secs() as f64 * 1e9 + duration.subsec_nanos() as f64
+    }
+
+    /// Only a rough check: a short-circuiting comparison of 4 KiB would be
+    /// orders of magnitude faster with an early mismatch, but timing on a
+    /// shared machine is too noisy to assert anything tighter, so this is
+    /// ignored by default. Run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn constant_time_eq_timing() {
+        let reference = vec![0x5a; 4096];
+        let mut early = reference.clone();
+        early[0] ^= 1;
+        let mut late = reference.clone();
+        late[4095] ^= 1;
+
+        let early = nanos(time_comparisons(&reference, &early));
+        let late = nanos(time_comparisons(&reference, &late));
+        let ratio = early / late;
+        assert!(ratio > 0.5 && ratio < 2.0, "early mismatch took {}ns, late took {}ns", early, late);
+    }
+}
@@ -1,61 +1,316 @@
 use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
 use std::io::{self, Cursor};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::{Async, AsyncSink, Poll, Stream, Sink};
 use bytes::{Buf, Bytes, BytesMut};
+use iovec::IoVec;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Decoder, Encoder, Framed, FramedParts};
 use msgio;
-use slog::Logger;
 
 use crypto::hash::{ Signer, Verifier };
 use crypto::cipher::{ Encryptor, Decryptor };
 use crypto::shared::SharedAlgorithms;
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+use config::RekeyLimits;
+use error::{Failure, failed, failed_with};
+use extensions::Extensions;
+use log::Log;
+use metrics::Metrics;
+use secret;
 
 #[derive(Debug)]
 pub struct SecStream<S> where S: AsyncRead + AsyncWrite {
-    logger: Logger,
+    log: Log,
+    extensions: Extensions,
     done: bool,
+    /// Whether our close record has been queued, after which nothing more
+    /// can be written.
+    closing: bool,
+    /// Set by the first error, which is then returned by every operation.
+    failed: Option<(io::ErrorKind, Failure, String)>,
     buffer: Cursor<Bytes>,
+    /// Control records generated while reading that are still to be sent.
+    replies: VecDeque<Frame>,
+    /// Whether replies have been sent but possibly not yet flushed.
+    replying: bool,
+    /// The most application data that fits in one frame once the record
+    /// type and MAC are added, larger writes are split.
+    max_data: usize,
+    channel_binding: Bytes,
+    curve: CurveAlgorithm,
+    cipher: CipherAlgorithm,
+    hash: HashAlgorithm,
+    traffic: Arc<Mutex<Traffic>>,
     inner: Framed<S, SecStreamCodec>,
 }
 
+/// Traffic counters for a single `SecStream`, as returned by
+/// `SecStream::stats`.
+///
+/// Plaintext counts only application data, ciphertext counts everything
+/// sent or received on the underlying transport after the handshake,
+/// including length prefixes, MACs and control records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub plaintext_sent: u64,
+    pub plaintext_received: u64,
+    pub ciphertext_sent: u64,
+    pub ciphertext_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// The largest frame sent or received, including its length prefix.
+    pub largest_frame: u64,
+    /// Key changes completed in both directions.
+    pub rekeys: u64,
+    /// Time since a frame was last sent or received, or since the stream
+    /// was created if neither has happened yet.
+    pub idle: Duration,
+}
+
+/// Counters updated by the codec, shared with the stream as the codec can't
+/// be reached once it's inside the `Framed`.
 #[derive(Debug)]
-struct SecStreamCodec {
+struct Traffic {
+    stats: Stats,
+    last_activity: Instant,
+}
+
+impl Traffic {
+    fn sent(&mut self, ciphertext: usize) {
+        self.stats.ciphertext_sent += ciphertext as u64;
+        self.stats.frames_sent += 1;
+        self.stats.largest_frame = cmp::max(self.stats.largest_frame, ciphertext as u64);
+        self.last_activity = Instant::now();
+    }
+
+    fn received(&mut self, ciphertext: usize) {
+        self.stats.ciphertext_received += ciphertext as u64;
+        self.stats.frames_received += 1;
+        self.stats.largest_frame = cmp::max(self.stats.largest_frame, ciphertext as u64);
+        self.last_activity = Instant::now();
+    }
+}
+
+/// Each direction's sequence number is capped well below where it could
+/// wrap, the stream fails rather than ever reusing a sequence number.
+///
+/// This doesn't bound the keystream used under one key: at up to 8 MiB a
+/// frame a cipher with a 64-bit block wraps long before this. Use
+/// `RekeyLimits::bytes` to change keys before that happens.
+const MAX_FRAMES: u64 = 1 << 48;
+
+/// The largest frame we will buffer or send, matching go-libp2p's secio.
+const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Record types, prefixed to the plaintext of every frame once an extension
+/// requiring control records has been negotiated.
+pub(crate) const RECORD_DATA: u8 = 0;
+pub(crate) const RECORD_REKEY: u8 = 1;
+pub(crate) const RECORD_REKEY_ACK: u8 = 2;
+pub(crate) const RECORD_CLOSE: u8 = 3;
+
+/// A record to send to the peer.
+#[derive(Debug)]
+pub(crate) enum Frame {
+    /// Application data, already preceded by its record type if typed
+    /// records were negotiated, so the record is encrypted in one call.
+    Data(Bytes),
+    /// Our half of a rekey exchange, a fresh ephemeral public key.
+    Rekey(Vec<u8>),
+    /// Sent once we know the next keys, every frame after this uses them.
+    RekeyAck,
+    /// The last record we will send.
+    Close,
+    /// A record sent exactly as given, whatever was negotiated, so tests can
+    /// script a peer breaking the protocol.
+    #[cfg(feature = "testing")]
+    Raw(Vec<u8>),
+}
+
+/// A record received from the peer.
+#[derive(Debug)]
+pub(crate) enum Inbound {
+    Data(Bytes),
+    /// Control records that must be sent in response.
+    Reply(Vec<Frame>),
+    /// The peer will not send anything more.
+    Close,
+}
+
+/// Everything needed to run a new ephemeral exchange after the handshake.
+#[derive(Debug)]
+pub(crate) struct RekeyParams {
+    pub(crate) curve: CurveAlgorithm,
+    pub(crate) hash: HashAlgorithm,
+    pub(crate) cipher: CipherAlgorithm,
+    /// Whether our keys are the first half of the stretched key material,
+    /// decided by the handshake ordering.
+    pub(crate) local_first: bool,
+    pub(crate) limits: Option<RekeyLimits>,
+}
+
+/// Completes an ephemeral exchange once given the peer's public key.
+///
+/// This must be `Send` so the codec, and so every `SecStream`, is too.
+type Agreement = Box<FnMut(&[u8]) -> io::Result<SharedAlgorithms> + Send>;
+
+/// Rekeying is a three record exchange: both sides send a `Rekey` record
+/// with a fresh ephemeral public key, whoever receives the first one
+/// replying with its own. Once a side knows both keys it sends a `RekeyAck`
+/// and switches its outbound keys, on receiving the peer's `RekeyAck` it
+/// switches its inbound keys. All control records are sent under the old
+/// keys, so the exchange works even if both sides initiate at once.
+struct Rekey {
+    params: RekeyParams,
+    /// Our private key, waiting for the peer's half of the exchange.
+    pending: Option<Agreement>,
+    /// Keys agreed on but not yet in use in both directions.
+    next: Option<SharedAlgorithms>,
+    sending_next: bool,
+    receiving_next: bool,
+    bytes: u64,
+    frames: u64,
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct SecStreamCodec {
     inner: msgio::LengthPrefixed,
     algos: SharedAlgorithms,
+    extensions: Extensions,
+    rekey: Option<Rekey>,
+    /// Number of frames encoded so far, the sequence number of the next
+    /// outbound frame.
+    sent: u64,
+    /// Number of frames decoded so far, the sequence number of the next
+    /// inbound frame.
+    received: u64,
+    /// Whether our close record has been encoded, after which we can't send
+    /// anything more, including replies to the peer's control records.
+    closed: bool,
+    metrics: Metrics,
+    traffic: Arc<Mutex<Traffic>>,
+}
+
+fn other(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
 }
 
 impl<S> SecStream<S> where S: AsyncRead + AsyncWrite {
-    pub(crate) fn new(logger: Logger, parts: FramedParts<S>, algos: SharedAlgorithms) -> SecStream<S> {
+    pub(crate) fn new(log: Log, parts: FramedParts<S>, algos: SharedAlgorithms, extensions: Extensions, rekey: RekeyParams, channel_binding: Bytes, metrics: Metrics) -> SecStream<S> {
+        let (curve, cipher, hash) = (rekey.curve, rekey.cipher, rekey.hash);
+        let max_data = MAX_FRAME_LEN - algos.digest_len() - extensions.typed_records() as usize;
+        let codec = SecStreamCodec::new(algos, extensions, rekey, metrics);
         SecStream {
-            logger,
+            log,
+            extensions,
             done: false,
+            closing: false,
+            failed: None,
             buffer: Cursor::new(Bytes::new()),
-            inner: Framed::from_parts(parts, SecStreamCodec::new(algos)),
+            replies: VecDeque::new(),
+            replying: false,
+            max_data,
+            channel_binding,
+            curve, cipher, hash,
+            traffic: codec.traffic.clone(),
+            inner: Framed::from_parts(parts, codec),
         }
     }
-}
 
-impl SecStreamCodec {
-    pub(crate) fn new(algos: SharedAlgorithms) -> SecStreamCodec {
-        let inner = msgio::LengthPrefixed(msgio::Prefix::BigEndianU32, msgio::Suffix::None);
-        SecStreamCodec { inner, algos }
+    /// Traffic counters for this stream so far.
+    pub fn stats(&self) -> Stats {
+        let traffic = self.traffic.lock().expect("traffic lock poisoned");
+        Stats { idle: traffic.last_activity.elapsed(), ..traffic.stats }
     }
 
-    fn decrypt_msg(&mut self, msg: &[u8]) -> io::Result<Bytes> {
-        // MAC is stored at the end of the message.
-        // Assume digest algorithm is the same in both directions, should add
-        // some way to get the digest size from the VerificationKey.
-        let data_len = msg.len() - self.algos.digest_len();
-        try!(self.algos.verify(&msg[..data_len], &msg[data_len..]).map_err(|_| io::Error::new(io::ErrorKind::Other, "MAC verification failed")));
-        let data = try!(self.algos.decrypt(&msg[..data_len]).map_err(|_| io::Error::new(io::ErrorKind::Other, "Encryption failed")));
-        Ok(Bytes::from(data))
+    /// Returns a reference to the underlying transport.
+    ///
+    /// Reading or writing directly will corrupt the secure stream.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
     }
-}
 
-impl<S> io::Read for SecStream<S> where S: AsyncRead + AsyncWrite {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    /// Returns a mutable reference to the underlying transport.
+    ///
+    /// Reading or writing directly will corrupt the secure stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    /// A SHA2-256 multihash of the handshake transcript, the same on both
+    /// ends of this session and different for every other session.
+    ///
+    /// Signing this alongside application-level credentials ties them to
+    /// this session, so they can't be relayed over another.
+    pub fn channel_binding(&self) -> &[u8] {
+        &self.channel_binding
+    }
+
+    /// The key exchange curve negotiated during the handshake.
+    pub fn curve(&self) -> CurveAlgorithm {
+        self.curve
+    }
+
+    /// The cipher negotiated during the handshake.
+    pub fn cipher(&self) -> CipherAlgorithm {
+        self.cipher
+    }
+
+    /// The MAC hash negotiated during the handshake.
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    fn send_replies(&mut self) -> io::Result<()> {
+        while let Some(frame) = self.replies.pop_front() {
+            if let AsyncSink::NotReady(frame) = self.inner.start_send(frame)? {
+                self.replies.push_front(frame);
+                break;
+            }
+            self.replying = true;
+        }
+        Ok(())
+    }
+
+    /// Once anything has gone wrong the cipher state can't be trusted to be
+    /// in sync with the peer, so every later operation fails the same way.
+    fn check_failed(&self) -> io::Result<()> {
+        match self.failed {
+            Some((kind, failure, ref msg)) => Err(failed_with(kind, failure, msg.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Writing after shutdown is a mistake by the caller rather than a sign
+    /// the stream is broken, so it's reported without failing the stream and
+    /// the read half carries on working.
+    fn check_open(&self) -> io::Result<()> {
+        if self.closing {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream has been shut down"));
+        }
+        Ok(())
+    }
+
+    fn fail(&mut self, err: io::Error) -> io::Error {
+        if err.kind() == io::ErrorKind::WouldBlock || self.failed.is_some() {
+            return err;
+        }
+        log!(warn, self.log, "Secure stream failed"; error = err);
+        self.failed = Some((err.kind(), Failure::of(&err), err.to_string()));
+        // Best effort, the error we already have is the one worth reporting.
+        let _ = self.inner.get_mut().shutdown();
+        err
+    }
+
+    fn read_frames(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         loop {
             if self.done {
                 return Ok(0);
@@ -67,11 +322,23 @@ impl<S> io::Read for SecStream<S> where S: AsyncRead + AsyncWrite {
                 return Ok(len);
             }
 
+            self.flush_replies()?;
+
             match self.inner.poll()? {
-                Async::Ready(Some(buffer)) => {
-                    self.buffer = Cursor::new(buffer);
+                Async::Ready(Some(Inbound::Data(buffer))) => {
+                    self.replace_buffer(buffer);
+                }
+                Async::Ready(Some(Inbound::Reply(frames))) => {
+                    log!(debug, self.log, "Rekeying at peer's request");
+                    self.replies.extend(frames);
+                }
+                Async::Ready(Some(Inbound::Close)) => {
+                    self.done = true;
                 }
                 Async::Ready(None) => {
+                    if self.extensions.close() {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended without a close record"));
+                    }
                     self.done = true;
                 }
                 Async::NotReady => {
@@ -80,54 +347,619 @@ impl<S> io::Read for SecStream<S> where S: AsyncRead + AsyncWrite {
             }
         }
     }
+
+    /// A buffer for `len` bytes of application data, with the record type
+    /// already written if needed.
+    ///
+    /// Copying the caller's data into this is the only copy made of the
+    /// plaintext, the codec encrypts it as is.
+    fn data_record(&self, len: usize) -> BytesMut {
+        let mut record = BytesMut::with_capacity(1 + len);
+        if self.extensions.typed_records() {
+            record.extend_from_slice(&[RECORD_DATA]);
+        }
+        record
+    }
+
+    fn write_frame(&mut self, data: Bytes) -> io::Result<usize> {
+        self.send_replies()?;
+        let len = data.len() - self.extensions.typed_records() as usize;
+        debug_assert!(len <= self.max_data, "{} bytes of data won't fit in a frame", len);
+        match self.inner.start_send(Frame::Data(data))? {
+            AsyncSink::Ready => Ok(len),
+            AsyncSink::NotReady(Frame::Data(data)) => {
+                secret::zero_bytes(data);
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "stream not ready to send"))
+            }
+            AsyncSink::NotReady(_) => unreachable!(),
+        }
+    }
+
+    fn flush_frames(&mut self) -> io::Result<()> {
+        loop {
+            self.send_replies()?;
+            match self.inner.poll_complete()? {
+                Async::Ready(()) => {
+                    if self.replies.is_empty() {
+                        self.replying = false;
+                        return Ok(());
+                    }
+                }
+                Async::NotReady => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "stream not done sending"));
+                }
+            }
+        }
+    }
+
+    fn close_frames(&mut self) -> Poll<(), io::Error> {
+        if self.extensions.close() && !self.closing {
+            // Any outstanding control records have to go before the close.
+            self.send_replies()?;
+            while !self.replies.is_empty() {
+                if let Async::NotReady = self.inner.poll_complete()? {
+                    return Ok(Async::NotReady);
+                }
+                self.send_replies()?;
+            }
+            if let AsyncSink::NotReady(_) = self.inner.start_send(Frame::Close)? {
+                return Ok(Async::NotReady);
+            }
+        }
+        self.closing = true;
+        self.inner.close()
+    }
+
+    /// Swap in a newly decrypted buffer, wiping the previous plaintext.
+    fn replace_buffer(&mut self, buffer: Bytes) {
+        let old = mem::replace(&mut self.buffer, Cursor::new(buffer));
+        secret::zero_bytes(old.into_inner());
+    }
+
+    /// Queue `record` to be sent as is on the next flush, see `Frame::Raw`.
+    #[cfg(feature = "testing")]
+    pub(crate) fn queue_record(&mut self, record: Vec<u8>) {
+        self.replies.push_back(Frame::Raw(record));
+    }
+
+    /// Push out any control records without blocking, so a stream that is
+    /// only being read from still answers the peer.
+    fn flush_replies(&mut self) -> io::Result<()> {
+        if self.replying || !self.replies.is_empty() {
+            self.send_replies()?;
+            if let Async::Ready(()) = self.inner.poll_complete()? {
+                self.replying = !self.replies.is_empty();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RekeyParams {
+    fn generate(&self) -> io::Result<(Vec<u8>, Agreement)> {
+        let mut key = self.curve.generate_priv_key()?;
+        let epubkey = key.pub_key()?.to_owned();
+        let (hash, cipher, local_first) = (self.hash, self.cipher, self.local_first);
+        let agreement: Agreement = Box::new(move |theirs: &[u8]| -> io::Result<SharedAlgorithms> {
+            Ok(key.agree_with(theirs, hash, cipher, local_first)?)
+        });
+        Ok((epubkey, agreement))
+    }
+}
+
+impl Rekey {
+    fn new(params: RekeyParams) -> Rekey {
+        Rekey {
+            params,
+            pending: None,
+            next: None,
+            sending_next: false,
+            receiving_next: false,
+            bytes: 0,
+            frames: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn in_progress(&self) -> bool {
+        self.pending.is_some() || self.next.is_some()
+    }
+
+    fn is_due(&self) -> bool {
+        if self.in_progress() {
+            return false;
+        }
+        match self.params.limits {
+            Some(limits) => {
+                limits.bytes.map_or(false, |bytes| self.bytes >= bytes)
+                    || limits.frames.map_or(false, |frames| self.frames >= frames)
+                    || limits.interval.map_or(false, |interval| self.since.elapsed() >= interval)
+            }
+            None => false,
+        }
+    }
+
+    /// Once the next keys are in use in both directions they become current.
+    fn finish(&mut self) -> Option<SharedAlgorithms> {
+        if !(self.sending_next && self.receiving_next) {
+            return None;
+        }
+        self.sending_next = false;
+        self.receiving_next = false;
+        self.bytes = 0;
+        self.frames = 0;
+        self.since = Instant::now();
+        self.next.take()
+    }
+}
+
+impl fmt::Debug for Rekey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rekey")
+            .field("params", &self.params)
+            .field("pending", &self.pending.is_some())
+            .field("next", &self.next.is_some())
+            .field("sending_next", &self.sending_next)
+            .field("receiving_next", &self.receiving_next)
+            .field("bytes", &self.bytes)
+            .field("frames", &self.frames)
+            .field("since", &self.since)
+            .finish()
+    }
+}
+
+impl SecStreamCodec {
+    pub(crate) fn new(algos: SharedAlgorithms, extensions: Extensions, rekey: RekeyParams, metrics: Metrics) -> SecStreamCodec {
+        let inner = msgio::LengthPrefixed(msgio::Prefix::BigEndianU32, msgio::Suffix::None);
+        let rekey = if extensions.rekey() { Some(Rekey::new(rekey)) } else { None };
+        let traffic = Arc::new(Mutex::new(Traffic { stats: Stats::default(), last_activity: Instant::now() }));
+        SecStreamCodec { inner, algos, extensions, rekey, sent: 0, received: 0, closed: false, metrics, traffic }
+    }
+
+    fn next_seq(counter: &mut u64, direction: &str) -> io::Result<u64> {
+        if *counter >= MAX_FRAMES {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("{} frame limit of {} reached, stream must be re-established", direction, MAX_FRAMES)));
+        }
+        let seq = *counter;
+        *counter += 1;
+        Ok(seq)
+    }
+
+    fn outbound(&mut self) -> &mut SharedAlgorithms {
+        match self.rekey {
+            Some(Rekey { sending_next: true, next: Some(ref mut next), .. }) => next,
+            _ => &mut self.algos,
+        }
+    }
+
+    fn inbound(&mut self) -> &mut SharedAlgorithms {
+        match self.rekey {
+            Some(Rekey { receiving_next: true, next: Some(ref mut next), .. }) => next,
+            _ => &mut self.algos,
+        }
+    }
+
+    fn data_received(&self, data: Bytes) -> Inbound {
+        self.traffic.lock().expect("traffic lock poisoned").stats.plaintext_received += data.len() as u64;
+        self.metrics.increment("secio_bytes_decrypted_total", &[], data.len() as u64);
+        Inbound::Data(data)
+    }
+
+    fn finish_rekey(&mut self) {
+        let next = match self.rekey {
+            Some(ref mut rekey) => rekey.finish(),
+            None => None,
+        };
+        if let Some(next) = next {
+            self.algos = next;
+            self.traffic.lock().expect("traffic lock poisoned").stats.rekeys += 1;
+        }
+    }
+
+    /// Verify and decrypt a frame, returning its plaintext.
+    ///
+    /// libp2p-crypto can only decrypt into a new `Vec`, which becomes the
+    /// stream's read buffer. Decrypting in place in the received `BytesMut`,
+    /// and so reusing one read buffer for every frame, needs an in-place
+    /// `Decryptor` in that crate first.
+    fn decrypt_msg(&mut self, msg: &[u8]) -> io::Result<Bytes> {
+        let seq = SecStreamCodec::next_seq(&mut self.received, "inbound")?;
+        let verified = {
+            let algos = self.inbound();
+            // MAC is stored at the end of the message.
+            // Assume digest algorithm is the same in both directions, should add
+            // some way to get the digest size from the VerificationKey.
+            if msg.len() < algos.digest_len() {
+                return Err(io::Error::new(io::ErrorKind::Other, format!("inbound frame #{} too short to contain a MAC", seq)));
+            }
+            let data_len = msg.len() - algos.digest_len();
+            match algos.verify(&msg[..data_len], &msg[data_len..]) {
+                Ok(_) => Some(algos.decrypt(&msg[..data_len])),
+                Err(_) => None,
+            }
+        };
+        let data = match verified {
+            Some(data) => try!(data.map_err(|_| io::Error::new(io::ErrorKind::Other, format!("Decryption failed on inbound frame #{}", seq)))),
+            None => {
+                self.metrics.increment("secio_mac_failures_total", &[], 1);
+                return Err(failed(Failure::Mac, format!("MAC verification failed on inbound frame #{}", seq)));
+            }
+        };
+        self.traffic.lock().expect("traffic lock poisoned").received(4 + msg.len());
+        self.metrics.increment("secio_frames_decrypted_total", &[], 1);
+        Ok(Bytes::from(data))
+    }
+
+    /// Encrypt `plaintext` as a single frame, writing the length prefix,
+    /// ciphertext and MAC straight into `dst`.
+    ///
+    /// libp2p-crypto can only encrypt into a new `Vec`, so that is the one
+    /// allocation made per frame.
+    pub(crate) fn encrypt_msg(&mut self, plaintext: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        let seq = SecStreamCodec::next_seq(&mut self.sent, "outbound")?;
+        let frame_len = {
+            let algos = self.outbound();
+
+            let start = dst.len();
+            dst.reserve(4 + plaintext.len() + algos.digest_len());
+            // Placeholder for the length prefix, filled in once we know it.
+            dst.extend_from_slice(&[0; 4]);
+
+            let data = algos.encrypt(plaintext).map_err(|_| io::Error::new(io::ErrorKind::Other, format!("Encryption failed on outbound frame #{}", seq)))?;
+            dst.extend_from_slice(&data);
+            let mac = algos.sign(&dst[start + 4..]);
+            dst.extend(mac);
+
+            let len = dst.len() - start - 4;
+            if len > u32::max_value() as usize {
+                return Err(other("frame too large for length prefix"));
+            }
+            dst[start..start + 4].copy_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+            4 + len
+        };
+        self.traffic.lock().expect("traffic lock poisoned").sent(frame_len);
+        self.metrics.increment("secio_frames_encrypted_total", &[], 1);
+        Ok(())
+    }
+
+    fn encode_frame(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        match frame {
+            Frame::Data(data) => {
+                let result = self.encrypt_msg(&data, dst);
+                secret::zero_bytes(data);
+                result
+            }
+            #[cfg(feature = "testing")]
+            Frame::Raw(record) => self.encrypt_msg(&record, dst),
+            _ if !self.extensions.typed_records() => Err(other("control records were not negotiated")),
+            Frame::Rekey(epubkey) => {
+                let mut record = Vec::with_capacity(1 + epubkey.len());
+                record.push(RECORD_REKEY);
+                record.extend_from_slice(&epubkey);
+                self.encrypt_msg(&record, dst)
+            }
+            Frame::RekeyAck => self.encrypt_msg(&[RECORD_REKEY_ACK], dst),
+            Frame::Close => self.encrypt_msg(&[RECORD_CLOSE], dst),
+        }
+    }
+
+    /// Start a rekey exchange if one of the configured limits was reached.
+    fn start_rekey_if_due(&mut self, dst: &mut BytesMut) -> io::Result<()> {
+        let epubkey = match self.rekey {
+            Some(ref mut rekey) if rekey.is_due() => {
+                let (epubkey, agreement) = rekey.params.generate()?;
+                rekey.pending = Some(agreement);
+                epubkey
+            }
+            _ => return Ok(()),
+        };
+        self.encode_frame(Frame::Rekey(epubkey), dst)
+    }
+
+    /// The peer sent its half of a rekey exchange, returns the records to
+    /// send in response.
+    fn rekey_requested(&mut self, epubkey: &[u8]) -> io::Result<Vec<Frame>> {
+        let rekey = match self.rekey {
+            Some(ref mut rekey) => rekey,
+            None => return Err(other("received rekey record without negotiating rekeying")),
+        };
+        if rekey.next.is_some() {
+            return Err(other("received rekey record while already rekeying"));
+        }
+        if self.closed && rekey.pending.is_none() {
+            // We can't send our half of the exchange, so the peer will just
+            // keep using the current keys.
+            return Ok(Vec::new());
+        }
+        let mut replies = Vec::new();
+        let mut agreement = match rekey.pending.take() {
+            Some(agreement) => agreement,
+            None => {
+                let (epubkey, agreement) = rekey.params.generate()?;
+                replies.push(Frame::Rekey(epubkey));
+                agreement
+            }
+        };
+        rekey.next = Some(agreement(epubkey)?);
+        // Even if we can't acknowledge we still need the next keys to read
+        // whatever the peer sends after its acknowledgement.
+        if !self.closed {
+            replies.push(Frame::RekeyAck);
+        }
+        Ok(replies)
+    }
+
+    /// The peer has switched its outbound keys, all following frames use the
+    /// next keys.
+    fn rekey_acked(&mut self) -> io::Result<()> {
+        match self.rekey {
+            Some(ref mut rekey) if rekey.next.is_some() && !rekey.receiving_next => {
+                rekey.receiving_next = true;
+            }
+            _ => return Err(other("received unexpected rekey acknowledgement")),
+        }
+        self.finish_rekey();
+        Ok(())
+    }
+}
+
+impl<S> io::Read for SecStream<S> where S: AsyncRead + AsyncWrite {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_failed()?;
+        let result = self.read_frames(buf);
+        result.map_err(|err| self.fail(err))
+    }
 }
 
 impl<S> io::Write for SecStream<S> where S: AsyncRead + AsyncWrite {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.inner.start_send(Bytes::from(buf))? {
-            AsyncSink::Ready => Ok(buf.len()),
-            AsyncSink::NotReady(_) => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream not ready to send")),
-        }
+        self.check_failed()?;
+        self.check_open()?;
+        let len = cmp::min(buf.len(), self.max_data);
+        let mut record = self.data_record(len);
+        record.extend_from_slice(&buf[..len]);
+        let result = self.write_frame(record.freeze());
+        result.map_err(|err| self.fail(err))
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match self.inner.poll_complete()? {
-            Async::Ready(()) => Ok(()),
-            Async::NotReady => Err(io::Error::new(io::ErrorKind::WouldBlock, "stream not done sending")),
-        }
+        self.check_failed()?;
+        let result = self.flush_frames();
+        result.map_err(|err| self.fail(err))
     }
 }
 
 impl<S> AsyncRead for SecStream<S> where S: AsyncRead + AsyncWrite {
 }
 
+impl<S> Drop for SecStream<S> where S: AsyncRead + AsyncWrite {
+    fn drop(&mut self) {
+        self.replace_buffer(Bytes::new());
+    }
+}
+
 impl<S> AsyncWrite for SecStream<S> where S: AsyncRead + AsyncWrite {
+    /// Shut down the write half of the stream, flushing any buffered frames
+    /// and, if the peer supports it, sending a close record. The stream can
+    /// still be read from until the peer closes its side as well.
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        self.inner.close()
+        self.check_failed()?;
+        let result = self.close_frames();
+        result.map_err(|err| self.fail(err))
+    }
+
+    /// Write every chunk of `buf` as a single frame, rather than a frame per
+    /// chunk, up to the most that fits in a frame.
+    ///
+    /// As with `write` the chunks are copied once, into the record that is
+    /// then encrypted.
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        self.check_failed()?;
+        self.check_open()?;
+        if !buf.has_remaining() {
+            return Ok(Async::Ready(0));
+        }
+
+        let data = {
+            static DUMMY: &[u8] = &[0];
+            let mut iovecs = [<&IoVec>::from(DUMMY); 64];
+            let count = buf.bytes_vec(&mut iovecs);
+            let len = cmp::min(iovecs[..count].iter().map(|iovec| iovec.len()).sum::<usize>(), self.max_data);
+            let mut data = self.data_record(len);
+            let mut remaining = len;
+            for iovec in &iovecs[..count] {
+                let chunk = &iovec[..cmp::min(iovec.len(), remaining)];
+                data.extend_from_slice(chunk);
+                remaining -= chunk.len();
+            }
+            data.freeze()
+        };
+
+        let result = match self.write_frame(data) {
+            Ok(len) => {
+                buf.advance(len);
+                Ok(Async::Ready(len))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(err) => Err(err),
+        };
+        result.map_err(|err| self.fail(err))
     }
 }
 
 impl Decoder for SecStreamCodec {
-    type Item = Bytes;
+    type Item = Inbound;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Ok(if let Some(msg) = self.inner.decode(src)? {
-            Some(self.decrypt_msg(&msg)?)
-        } else {
-            None
-        })
+        loop {
+            if src.len() >= 4 {
+                let len = (src[0] as usize) << 24 | (src[1] as usize) << 16 | (src[2] as usize) << 8 | src[3] as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("inbound frame of {} bytes exceeds maximum of {}", len, MAX_FRAME_LEN)));
+                }
+            }
+
+            let msg = match self.inner.decode(src)? {
+                Some(msg) => msg,
+                None => return Ok(None),
+            };
+            let mut record = self.decrypt_msg(&msg)?;
+
+            if !self.extensions.typed_records() {
+                return Ok(Some(self.data_received(record)));
+            }
+
+            if record.is_empty() {
+                return Err(other("received record without a type"));
+            }
+
+            match record[0] {
+                RECORD_DATA => {
+                    // Unlike slicing, advancing keeps the plaintext as the
+                    // sole owner of the decrypted Vec, so nothing more is
+                    // allocated and it can be wiped in place once read.
+                    record.advance(1);
+                    return Ok(Some(self.data_received(record)));
+                }
+                RECORD_REKEY => {
+                    return Ok(Some(Inbound::Reply(self.rekey_requested(&record[1..])?)));
+                }
+                RECORD_REKEY_ACK => {
+                    self.rekey_acked()?;
+                }
+                RECORD_CLOSE if self.extensions.close() => {
+                    return Ok(Some(Inbound::Close));
+                }
+                _ => {
+                    return Err(other("received record of unknown type"));
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(item) => Ok(Some(item)),
+            None if src.is_empty() => Ok(None),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended partway through a frame")),
+        }
     }
 }
 
 impl Encoder for SecStreamCodec {
-    type Item = Bytes;
+    type Item = Frame;
     type Error = io::Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut data = self.algos.encrypt(&item).map_err(|_| io::Error::new(io::ErrorKind::Other, "Encryption failed"))?;
-        let mac = self.algos.sign(&data);
-        data.extend(mac);
-        self.inner.encode(Bytes::from(data), dst)
+        let data_len = match item {
+            Frame::Data(ref data) => Some(data.len() - self.extensions.typed_records() as usize),
+            _ => None,
+        };
+        let acking = match item {
+            Frame::RekeyAck => true,
+            _ => false,
+        };
+        let closing = match item {
+            Frame::Close => true,
+            _ => false,
+        };
+
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream has been shut down"));
+        }
+
+        if data_len.is_some() {
+            self.start_rekey_if_due(dst)?;
+        }
+
+        self.encode_frame(item, dst)?;
+
+        if let Some(len) = data_len {
+            self.traffic.lock().expect("traffic lock poisoned").stats.plaintext_sent += len as u64;
+            self.metrics.increment("secio_bytes_encrypted_total", &[], len as u64);
+        }
+
+        if let Some(ref mut rekey) = self.rekey {
+            if let Some(len) = data_len {
+                rekey.bytes += len as u64;
+                rekey.frames += 1;
+            }
+            if acking {
+                rekey.sending_next = true;
+            }
+        }
+        if acking {
+            self.finish_rekey();
+        }
+        if closing {
+            self.closed = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+    use tokio_core::net::TcpStream;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+
+    use extensions::Extensions;
+    use metrics::Metrics;
+    use super::{Frame, Inbound, MAX_FRAMES, RECORD_DATA, RekeyParams, SecStream, SecStreamCodec};
+
+    fn assert_send<T: Send>() {
+    }
+
+    #[test]
+    fn secstream_is_send() {
+        assert_send::<SecStream<TcpStream>>();
+    }
+
+    /// Codecs for either end of a stream, sharing a fresh set of keys.
+    fn pair() -> (SecStreamCodec, SecStreamCodec) {
+        let (curve, cipher, hash) = (CurveAlgorithm::all()[0], CipherAlgorithm::all()[0], HashAlgorithm::all()[0]);
+        let mut ours = curve.generate_priv_key().unwrap();
+        let mut theirs = curve.generate_priv_key().unwrap();
+        let our_pubkey = ours.pub_key().unwrap().to_owned();
+        let their_pubkey = theirs.pub_key().unwrap().to_owned();
+        let our_algos = ours.agree_with(&their_pubkey, hash, cipher, true).unwrap();
+        let their_algos = theirs.agree_with(&our_pubkey, hash, cipher, false).unwrap();
+        let params = |local_first| RekeyParams { curve, hash, cipher, local_first, limits: None };
+        (SecStreamCodec::new(our_algos, Extensions::supported(), params(true), Metrics::default()),
+         SecStreamCodec::new(their_algos, Extensions::supported(), params(false), Metrics::default()))
+    }
+
+    fn data() -> Frame {
+        Frame::Data(Bytes::from(&[RECORD_DATA, 42][..]))
+    }
+
+    #[test]
+    fn outbound_frame_limit() {
+        let (mut ours, _) = pair();
+        ours.sent = MAX_FRAMES - 1;
+        let mut dst = BytesMut::new();
+        ours.encode(data(), &mut dst).unwrap();
+        let err = ours.encode(data(), &mut dst).unwrap_err();
+        assert!(err.to_string().contains("outbound frame limit"), "{:?}", err);
+    }
+
+    #[test]
+    fn inbound_frame_limit() {
+        let (mut ours, mut theirs) = pair();
+        ours.received = MAX_FRAMES - 1;
+        let mut src = BytesMut::new();
+        theirs.encode(data(), &mut src).unwrap();
+        theirs.encode(data(), &mut src).unwrap();
+        match ours.decode(&mut src).unwrap() {
+            Some(Inbound::Data(ref data)) if &data[..] == &[42][..] => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let err = ours.decode(&mut src).unwrap_err();
+        assert!(err.to_string().contains("inbound frame limit"), "{:?}", err);
     }
 }
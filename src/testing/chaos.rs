@@ -0,0 +1,232 @@
+use std::cmp;
+use std::io;
+use std::mem;
+
+use futures::{Async, Poll};
+use futures::task;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::SeededRng;
+
+/// Wraps a transport, injecting faults chosen by a seed so that failures
+/// are reproducible.
+///
+/// By default every fault is disabled and the wrapper is transparent.
+#[derive(Debug)]
+pub struct ChaosTransport<S> {
+    inner: S,
+    rng: SeededRng,
+    delay: bool,
+    fragment: bool,
+    reorder: bool,
+    corrupt: Option<u64>,
+    disconnect: Option<u64>,
+    /// Bytes to write before delaying, fragmenting or reordering.
+    after: u64,
+    written: u64,
+    disconnected: bool,
+    /// Writes held back until the next flush when reordering.
+    held: Vec<Vec<u8>>,
+    /// Reordered writes not yet accepted by the inner transport.
+    outgoing: Vec<u8>,
+}
+
+impl<S> ChaosTransport<S> {
+    pub fn new(inner: S, seed: u64) -> ChaosTransport<S> {
+        ChaosTransport {
+            inner,
+            rng: SeededRng::new(seed),
+            delay: false,
+            fragment: false,
+            reorder: false,
+            corrupt: None,
+            disconnect: None,
+            after: 0,
+            written: 0,
+            disconnected: false,
+            held: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Randomly report reads and writes as not ready, waking the task to
+    /// try again.
+    pub fn delay(mut self) -> Self {
+        self.delay = true;
+        self
+    }
+
+    /// Read and write at most one byte at a time, with `reorder` the bytes
+    /// are shuffled individually.
+    pub fn fragment(mut self) -> Self {
+        self.fragment = true;
+        self
+    }
+
+    /// Shuffle the writes made between each flush.
+    pub fn reorder(mut self) -> Self {
+        self.reorder = true;
+        self
+    }
+
+    /// Flip a bit of the byte written at `offset` in the outbound stream.
+    pub fn corrupt(mut self, offset: u64) -> Self {
+        self.corrupt = Some(offset);
+        self
+    }
+
+    /// Shut down the transport once `bytes` have been written, failing any
+    /// further reads or writes.
+    pub fn disconnect(mut self, bytes: u64) -> Self {
+        self.disconnect = Some(bytes);
+        self
+    }
+
+    /// Only delay, fragment and reorder once `bytes` have been written, for
+    /// example to let a handshake complete undisturbed.
+    pub fn after(mut self, bytes: u64) -> Self {
+        self.after = bytes;
+        self
+    }
+
+    /// The number of bytes passed on to the inner transport so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    fn active(&self) -> bool {
+        self.written >= self.after
+    }
+
+    fn stall(&mut self) -> bool {
+        if self.delay && self.active() && self.rng.next() % 4 == 0 {
+            task::current().notify();
+            return true;
+        }
+        false
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "chaos delay")
+}
+
+impl<S: AsyncWrite> ChaosTransport<S> {
+    /// Pass some bytes on to the inner transport, applying every fault
+    /// except reordering.
+    fn emit(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.disconnected {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "chaos disconnect"));
+        }
+
+        let mut len = buf.len();
+        if self.fragment && self.active() {
+            len = cmp::min(len, 1);
+        }
+        if let Some(limit) = self.disconnect {
+            if self.written >= limit {
+                self.disconnected = true;
+                // The peer notices the disconnect as end of stream.
+                self.inner.shutdown()?;
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "chaos disconnect"));
+            }
+            len = cmp::min(len as u64, limit - self.written) as usize;
+        }
+
+        let mut chunk = buf[..len].to_vec();
+        if let Some(offset) = self.corrupt {
+            if offset >= self.written && offset < self.written + len as u64 {
+                chunk[(offset - self.written) as usize] ^= 1 << (self.rng.next() % 8);
+            }
+        }
+
+        let written = self.inner.write(&chunk)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        if !self.held.is_empty() {
+            let mut held = mem::replace(&mut self.held, Vec::new());
+            for i in (1..held.len()).rev() {
+                let j = (self.rng.next() % (i as u64 + 1)) as usize;
+                held.swap(i, j);
+            }
+            for write in held {
+                self.outgoing.extend_from_slice(&write);
+            }
+        }
+
+        while !self.outgoing.is_empty() {
+            let outgoing = mem::replace(&mut self.outgoing, Vec::new());
+            match self.emit(&outgoing) {
+                Ok(written) => self.outgoing = outgoing[written..].to_vec(),
+                Err(err) => {
+                    self.outgoing = outgoing;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: io::Read> io::Read for ChaosTransport<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.disconnected {
+            return Ok(0);
+        }
+        if self.stall() {
+            return Err(would_block());
+        }
+        let len = if self.fragment && self.active() { cmp::min(buf.len(), 1) } else { buf.len() };
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<S: AsyncWrite> io::Write for ChaosTransport<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stall() {
+            return Err(would_block());
+        }
+        if self.reorder && self.active() {
+            let len = if self.fragment { cmp::min(buf.len(), 1) } else { buf.len() };
+            self.held.push(buf[..len].to_vec());
+            return Ok(len);
+        }
+        self.emit(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stall() {
+            return Err(would_block());
+        }
+        self.release()?;
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for ChaosTransport<S> {
+}
+
+impl<S: AsyncWrite> AsyncWrite for ChaosTransport<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if self.disconnected {
+            return Ok(Async::Ready(()));
+        }
+        match io::Write::flush(self) {
+            Ok(()) => self.inner.shutdown(),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Helpers for exercising secio without a real network, only available with
+//! the `testing` feature.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::rc::Rc;
+
+use bytes::BytesMut;
+use futures::{Async, Future, Poll};
+use futures::prelude::{await, async};
+use futures::task::{self, Task};
+use identity::{HostId, PeerId};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::FramedParts;
+
+use config::HandshakeConfig;
+use error::Failure;
+use handshake::handshake;
+use rng::Rng;
+use secstream::SecStream;
+
+mod chaos;
+mod peer;
+
+pub use self::chaos::ChaosTransport;
+pub use self::peer::{Misbehaviour, misbehave};
+
+#[derive(Debug, Default)]
+struct Half {
+    buffer: BytesMut,
+    closed: bool,
+    reader: Option<Task>,
+}
+
+/// One end of an in-memory, bidirectional, unbounded byte pipe.
+#[derive(Debug)]
+pub struct Pipe {
+    read: Rc<RefCell<Half>>,
+    write: Rc<RefCell<Half>>,
+}
+
+/// Create both ends of a connected in-memory pipe.
+pub fn pipe() -> (Pipe, Pipe) {
+    let a = Rc::new(RefCell::new(Half::default()));
+    let b = Rc::new(RefCell::new(Half::default()));
+    (Pipe { read: a.clone(), write: b.clone() }, Pipe { read: b, write: a })
+}
+
+/// Wrap a transport ready to be passed to `handshake`.
+pub fn parts<S>(inner: S) -> FramedParts<S> {
+    FramedParts { inner, readbuf: BytesMut::new(), writebuf: BytesMut::new() }
+}
+
+/// A deterministic, and utterly insecure, random number generator for
+/// reproducible handshake proposals.
+///
+/// Ephemeral keys don't come from this, see `HandshakeConfig::rng`.
+#[derive(Clone, Debug)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        // xorshift gets stuck at zero
+        SeededRng { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+
+    fn next(&mut self) -> u64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        for chunk in buf.chunks_mut(8) {
+            let value = self.next();
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (value >> (i * 8)) as u8;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn host(key: &[u8], pub_key: &[u8]) -> HostId {
+    HostId::from_der(key.to_owned(), pub_key.to_owned()).expect("test keys are valid")
+}
+
+/// A fixed RSA identity.
+pub fn alice() -> HostId {
+    host(include_bytes!("../../tests/keys/alice.der"), include_bytes!("../../tests/keys/alice.pub.der"))
+}
+
+/// A second fixed RSA identity, distinct from `alice`.
+pub fn bob() -> HostId {
+    host(include_bytes!("../../tests/keys/bob.der"), include_bytes!("../../tests/keys/bob.pub.der"))
+}
+
+/// Handshake as `alice` and `bob` over a fresh pipe, returning their ends
+/// of the secure stream.
+#[async]
+pub fn connect(alice_config: HandshakeConfig, bob_config: HandshakeConfig) -> io::Result<(SecStream<Pipe>, SecStream<Pipe>)> {
+    let (a, b) = pipe();
+    await!(connect_over(alice_config, a, bob_config, b))
+}
+
+/// Handshake as `alice` and `bob` over the given ends of a transport.
+#[async]
+pub fn connect_over<A, B>(alice_config: HandshakeConfig, a: A, bob_config: HandshakeConfig, b: B) -> io::Result<(SecStream<A>, SecStream<B>)>
+    where A: AsyncRead + AsyncWrite + 'static, B: AsyncRead + AsyncWrite + 'static
+{
+    let a = handshake(alice_config, parts(a), alice(), PeerId::Unknown);
+    let b = handshake(bob_config, parts(b), bob(), PeerId::Unknown);
+    let ((_, a), (_, b)) = await!(a.join(b))?;
+    Ok((a, b))
+}
+
+/// A future shutting down the write half of `W`, resolving back to it.
+#[derive(Debug)]
+pub struct Shutdown<W>(Option<W>);
+
+/// Shut down the write half of a stream, as `tokio_io::io::flush` does for
+/// flushing.
+pub fn shutdown<W: AsyncWrite>(writer: W) -> Shutdown<W> {
+    Shutdown(Some(writer))
+}
+
+impl<W: AsyncWrite> Future for Shutdown<W> {
+    type Item = W;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<W, io::Error> {
+        match self.0.as_mut().expect("polled after completion").shutdown()? {
+            Async::Ready(()) => Ok(Async::Ready(self.0.take().unwrap())),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Pipe {
+    /// The number of bytes written by the other end not yet read by this end.
+    pub fn pending(&self) -> usize {
+        self.read.borrow().buffer.len()
+    }
+
+    /// Flip a single bit in the data waiting to be read by this end, counting
+    /// from the most significant bit of the first pending byte.
+    pub fn flip_bit(&self, bit: usize) {
+        self.read.borrow_mut().buffer[bit / 8] ^= 0x80 >> (bit % 8);
+    }
+}
+
+/// How `err` is classified, as the `kind` label the metrics would give it.
+pub fn failure(err: &io::Error) -> &'static str {
+    Failure::of(err).label()
+}
+
+impl Half {
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+        if let Some(reader) = self.reader.take() {
+            reader.notify();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(reader) = self.reader.take() {
+            reader.notify();
+        }
+    }
+}
+
+impl io::Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut half = self.read.borrow_mut();
+        if half.buffer.is_empty() {
+            if half.closed {
+                return Ok(0);
+            }
+            half.reader = Some(task::current());
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "pipe empty"));
+        }
+        let len = cmp::min(buf.len(), half.buffer.len());
+        buf[..len].copy_from_slice(&half.buffer[..len]);
+        half.buffer.advance(len);
+        Ok(len)
+    }
+}
+
+impl io::Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut half = self.write.borrow_mut();
+        if half.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed"));
+        }
+        half.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Pipe {
+}
+
+impl AsyncWrite for Pipe {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.write.borrow_mut().close();
+        Ok(().into())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.write.borrow_mut().close();
+    }
+}
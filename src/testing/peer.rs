@@ -0,0 +1,243 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::io;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use futures::{Future, Sink, Stream};
+use futures::prelude::{await, async};
+use identity::PeerId;
+use msgio;
+use protobuf::{Message, parse_from_bytes};
+use tokio_io::codec::Framed;
+use tokio_io::io::{flush, read_exact, write_all};
+
+use config::HandshakeConfig;
+use data::{Propose, Exchange};
+use extensions::Extensions;
+use handshake::{NONCE_SIZE, channel_binding, corpus, handshake, order, pbetio, select};
+use log::Log;
+use metrics::Metrics;
+use rng::Rng;
+use secstream::{RECORD_REKEY, RECORD_REKEY_ACK, RekeyParams, SecStream};
+use super::{Half, Pipe, SeededRng, alice, bob, parts, pipe};
+
+/// A way for the scripted peer in `misbehave` to break the protocol.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// Disconnect without sending anything.
+    Disconnect,
+    /// Send a proposal that can't be parsed.
+    MalformedProposal,
+    /// Announce a proposal larger than any peer will accept.
+    OversizedProposal,
+    /// Propose only algorithms nobody supports.
+    NoCommonAlgorithms,
+    /// Propose with a public key other than the one expected.
+    MismatchedPubkey,
+    /// Send the other side's own proposal back to it.
+    ReflectProposal,
+    /// Sign the exchange over the proposals in the wrong order.
+    BadSignature,
+    /// Echo back something other than the other side's nonce.
+    WrongNonce,
+    /// Announce a frame larger than any peer will accept.
+    OversizedFrame,
+    /// Send a frame too short to hold a MAC.
+    ShortFrame,
+    /// Flip a bit in the MAC of the first data frame.
+    FlippedMac,
+    /// Disconnect halfway through the first data frame.
+    Truncated,
+    /// Send a record without a record type.
+    UntypedRecord,
+    /// Send a record of a type nobody has defined.
+    UnknownRecord,
+    /// Acknowledge a rekey that never started.
+    UnexpectedRekeyAck,
+    /// Start a second rekey before the first has finished.
+    DuplicateRekey,
+    /// Start a rekey without having advertised rekeying.
+    UnnegotiatedRekey,
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "alice disconnected")
+}
+
+/// Play `alice`'s side of a handshake against a scripted `bob` that
+/// misbehaves as described, then read 5 bytes from the resulting stream.
+///
+/// Resolves to whatever error `alice` encounters.
+#[async]
+pub fn misbehave(misbehaviour: Misbehaviour) -> io::Result<()> {
+    let (a, b) = pipe();
+    let tamper = a.read.clone();
+    let bob_id = PeerId::from_protobuf(&bob().pub_key().to_protobuf()?)?;
+
+    let alice = handshake(HandshakeConfig::new(), parts(a), alice(), bob_id)
+        .and_then(|(_, stream)| read_exact(stream, [0; 5]));
+    let bob = play_bob(misbehaviour, b, tamper).then(|_| Ok::<(), io::Error>(()));
+    await!(alice.join(bob))?;
+    Ok(())
+}
+
+#[async]
+fn play_bob(misbehaviour: Misbehaviour, transport: Pipe, tamper: Rc<RefCell<Half>>) -> io::Result<()> {
+    if misbehaviour == Misbehaviour::Disconnect {
+        return Ok(());
+    }
+
+    let transport = Framed::from_parts(parts(transport), msgio::LengthPrefixed(msgio::Prefix::BigEndianU32, msgio::Suffix::None));
+    let host = bob();
+
+    // Unlike a real peer, wait for alice's proposal first so it can be
+    // reflected.
+    let (alice_proposal_bytes, transport) = await!(transport.into_future().map_err(|(err, _)| err))?;
+    let alice_proposal_bytes = alice_proposal_bytes.ok_or_else(eof)?;
+    let alice_proposal: Propose = parse_from_bytes(&alice_proposal_bytes).map_err(pbetio)?;
+
+    if misbehaviour == Misbehaviour::OversizedProposal {
+        tamper.borrow_mut().push(&[0x01, 0x00, 0x00, 0x00]);
+        return Ok(());
+    }
+
+    let mut nonce = [0; NONCE_SIZE];
+    SeededRng::new(1).fill(&mut nonce)?;
+
+    let bob_proposal_bytes = match misbehaviour {
+        Misbehaviour::MalformedProposal => Bytes::from(&b"\xff\xff\xff not a proposal"[..]),
+        Misbehaviour::ReflectProposal => Bytes::from(&alice_proposal_bytes[..]),
+        _ => {
+            let mut proposal = Propose::new();
+            proposal.set_rand(nonce.to_vec());
+            let key = if misbehaviour == Misbehaviour::MismatchedPubkey { alice() } else { bob() };
+            proposal.set_pubkey(key.pub_key().to_protobuf()?);
+            if misbehaviour == Misbehaviour::NoCommonAlgorithms {
+                proposal.set_exchanges("none".to_owned());
+                proposal.set_ciphers("none".to_owned());
+                proposal.set_hashes("none".to_owned());
+            } else if misbehaviour == Misbehaviour::UnnegotiatedRekey {
+                // Keep the other extensions, so records are still typed.
+                let exchanges: Vec<&str> = alice_proposal.get_exchanges().split(',')
+                    .filter(|&entry| !Extensions::from_list(entry).rekey())
+                    .collect();
+                proposal.set_exchanges(exchanges.join(","));
+                proposal.set_ciphers(alice_proposal.get_ciphers().to_owned());
+                proposal.set_hashes(alice_proposal.get_hashes().to_owned());
+            } else {
+                proposal.set_exchanges(alice_proposal.get_exchanges().to_owned());
+                proposal.set_ciphers(alice_proposal.get_ciphers().to_owned());
+                proposal.set_hashes(alice_proposal.get_hashes().to_owned());
+            }
+            Bytes::from(proposal.write_to_bytes().map_err(pbetio)?)
+        }
+    };
+
+    let transport = await!(transport.send(bob_proposal_bytes.clone()))?;
+
+    match misbehaviour {
+        Misbehaviour::MalformedProposal
+            | Misbehaviour::NoCommonAlgorithms
+            | Misbehaviour::MismatchedPubkey
+            | Misbehaviour::ReflectProposal => return Ok(()),
+        _ => (),
+    }
+
+    let bob_proposal: Propose = parse_from_bytes(&bob_proposal_bytes).map_err(pbetio)?;
+    let order = order(bob_proposal.get_pubkey(), &nonce, alice_proposal.get_pubkey(), alice_proposal.get_rand());
+    let (curve, cipher, hash) = select(&HandshakeConfig::new(), &alice_proposal, order)?;
+
+    let mut ephemeral_priv_key = curve.generate_priv_key()?;
+    let signed = if misbehaviour == Misbehaviour::BadSignature {
+        corpus(&alice_proposal_bytes, &bob_proposal_bytes, ephemeral_priv_key.pub_key()?)
+    } else {
+        corpus(&bob_proposal_bytes, &alice_proposal_bytes, ephemeral_priv_key.pub_key()?)
+    };
+
+    let exchange = {
+        let mut exchange = Exchange::new();
+        exchange.set_epubkey(ephemeral_priv_key.pub_key()?.to_owned());
+        exchange.set_signature(host.sign(&signed)?);
+        exchange
+    };
+    let exchange_bytes = Bytes::from(exchange.write_to_bytes().map_err(pbetio)?);
+    let transport = await!(transport.send(exchange_bytes.clone()))?;
+
+    if misbehaviour == Misbehaviour::BadSignature {
+        return Ok(());
+    }
+
+    let (alice_exchange_bytes, transport) = await!(transport.into_future().map_err(|(err, _)| err))?;
+    let alice_exchange_bytes = alice_exchange_bytes.ok_or_else(eof)?;
+    let alice_exchange: Exchange = parse_from_bytes(&alice_exchange_bytes).map_err(pbetio)?;
+
+    let algos = ephemeral_priv_key.agree_with(alice_exchange.get_epubkey(), hash, cipher, order == Ordering::Less)?;
+    let extensions = Extensions::from_list(bob_proposal.get_exchanges()) & Extensions::from_list(alice_proposal.get_exchanges());
+    let rekey = RekeyParams {
+        curve, hash, cipher,
+        local_first: order == Ordering::Less,
+        limits: None,
+    };
+    let binding = channel_binding(order, &bob_proposal_bytes, &alice_proposal_bytes, &exchange_bytes, &alice_exchange_bytes);
+    let stream = SecStream::new(Log::default(), transport.into_parts(), algos, extensions, rekey, binding, Metrics::default());
+
+    let echo = if misbehaviour == Misbehaviour::WrongNonce {
+        vec![0; NONCE_SIZE]
+    } else {
+        alice_proposal.get_rand().to_owned()
+    };
+    let (stream, _) = await!(write_all(stream, echo))?;
+    let mut stream = await!(flush(stream))?;
+
+    match misbehaviour {
+        Misbehaviour::OversizedFrame => {
+            tamper.borrow_mut().push(&[0xff, 0xff, 0xff, 0xff]);
+        }
+        Misbehaviour::ShortFrame => {
+            tamper.borrow_mut().push(&[0, 0, 0, 1, 0]);
+        }
+        Misbehaviour::FlippedMac => {
+            let (stream, _) = await!(write_all(stream, b"hello"))?;
+            await!(flush(stream))?;
+            let mut half = tamper.borrow_mut();
+            let last = half.buffer.len() - 1;
+            half.buffer[last] ^= 1;
+        }
+        Misbehaviour::Truncated => {
+            // Alice may not have read the nonce echo yet, only cut into the
+            // frame written here.
+            let before = tamper.borrow().buffer.len();
+            let (stream, _) = await!(write_all(stream, b"hello"))?;
+            await!(flush(stream))?;
+            let mut half = tamper.borrow_mut();
+            let after = half.buffer.len();
+            half.buffer.truncate(before + (after - before) / 2);
+        }
+        Misbehaviour::UntypedRecord => {
+            stream.queue_record(Vec::new());
+            await!(flush(stream))?;
+        }
+        Misbehaviour::UnknownRecord => {
+            stream.queue_record(vec![0xff]);
+            await!(flush(stream))?;
+        }
+        Misbehaviour::UnexpectedRekeyAck => {
+            stream.queue_record(vec![RECORD_REKEY_ACK]);
+            await!(flush(stream))?;
+        }
+        Misbehaviour::DuplicateRekey | Misbehaviour::UnnegotiatedRekey => {
+            let mut key = curve.generate_priv_key()?;
+            let mut record = vec![RECORD_REKEY];
+            record.extend_from_slice(key.pub_key()?);
+            if misbehaviour == Misbehaviour::DuplicateRekey {
+                stream.queue_record(record.clone());
+            }
+            stream.queue_record(record);
+            await!(flush(stream))?;
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
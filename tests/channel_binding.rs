@@ -0,0 +1,24 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+
+use tokio_core::reactor::Core;
+
+use secio::HandshakeConfig;
+use secio::testing;
+
+#[test]
+fn both_sides_agree() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    assert!(!alice.channel_binding().is_empty());
+    assert_eq!(alice.channel_binding(), bob.channel_binding());
+}
+
+#[test]
+fn sessions_differ() {
+    let mut core = Core::new().unwrap();
+    let (first, _) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    let (second, _) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    assert_ne!(first.channel_binding(), second.channel_binding());
+}
@@ -0,0 +1,192 @@
+extern crate futures_await as futures;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{self, Read, Write};
+
+use futures::{Future, future};
+use identity::PeerId;
+use tokio_core::reactor::Core;
+use tokio_io::io::{flush, read_exact, write_all};
+
+use secio::{HandshakeConfig, SecStream};
+use secio::testing::{self, ChaosTransport, Pipe};
+
+const SEEDS: u64 = 16;
+
+/// Sent by alice once the handshake is done.
+const MESSAGE: &[u8] = b"sent after the handshake";
+
+/// The size of the nonce echoed back at the end of the handshake.
+const NONCE_SIZE: u64 = 16;
+
+/// Offsets in what alice writes when she handshakes then sends `MESSAGE`.
+struct Layout {
+    /// The nonce echo, the first frame after the handshake messages.
+    echo: u64,
+    echo_mac: u64,
+    /// The frame carrying `MESSAGE`.
+    data: u64,
+    data_mac: u64,
+    end: u64,
+}
+
+impl Layout {
+    fn measure() -> Layout {
+        let mut core = Core::new().unwrap();
+        let (a, b) = testing::pipe();
+        let (alice, _bob) = core.run(testing::connect_over(HandshakeConfig::new(), ChaosTransport::new(a, 0), HandshakeConfig::new(), b)).unwrap();
+        let data = alice.get_ref().written();
+        let echo = data - alice.stats().ciphertext_sent;
+        let alice = core.run(write_all(alice, MESSAGE).and_then(|(alice, _)| flush(alice))).unwrap();
+        // Each frame is a length prefix, a record type, the data then a MAC.
+        Layout {
+            echo,
+            echo_mac: echo + 5 + NONCE_SIZE,
+            data,
+            data_mac: data + 5 + MESSAGE.len() as u64,
+            end: alice.get_ref().written(),
+        }
+    }
+}
+
+/// Handshake over `a` and `b` then send `messages` frames of `MESSAGE`
+/// from alice to bob in one flush, returning what bob read.
+fn send(a: ChaosTransport<Pipe>, b: Pipe, messages: usize) -> io::Result<Vec<u8>> {
+    let mut core = Core::new().unwrap();
+    let alice = secio::handshake(HandshakeConfig::new(), testing::parts(a), testing::alice(), PeerId::Unknown)
+        .and_then(move |(_, alice)| {
+            let mut writes: Box<Future<Item=SecStream<ChaosTransport<Pipe>>, Error=io::Error>> = Box::new(future::ok(alice));
+            for _ in 0..messages {
+                writes = Box::new(writes.and_then(|alice| write_all(alice, MESSAGE).map(|(alice, _)| alice)));
+            }
+            writes
+        })
+        .and_then(flush)
+        // Dropping alice closes the pipe, so bob never waits forever.
+        .then(|_| Ok::<(), io::Error>(()));
+    let bob = secio::handshake(HandshakeConfig::new(), testing::parts(b), testing::bob(), PeerId::Unknown)
+        .and_then(move |(_, bob)| read_exact(bob, vec![0; MESSAGE.len() * messages]))
+        .map(|(_, received)| received);
+    core.run(alice.join(bob)).map(|((), received)| received)
+}
+
+fn round_trip(a: ChaosTransport<Pipe>, b: ChaosTransport<Pipe>) {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect_over(HandshakeConfig::new(), a, HandshakeConfig::new(), b)).unwrap();
+
+    let message = (0..1000u32).map(|i| i as u8).collect::<Vec<u8>>();
+    let write = write_all(alice, message.clone()).and_then(|(alice, _)| flush(alice));
+    let read = read_exact(bob, vec![0; message.len()]);
+    let (_, (_, received)) = core.run(write.join(read)).unwrap();
+    assert_eq!(received, message);
+}
+
+fn handshake_fails(a: ChaosTransport<Pipe>, b: ChaosTransport<Pipe>) -> bool {
+    let mut core = Core::new().unwrap();
+    core.run(testing::connect_over(HandshakeConfig::new(), a, HandshakeConfig::new(), b)).is_err()
+}
+
+#[test]
+fn one_byte_fragments() {
+    for seed in 0..SEEDS {
+        let (a, b) = testing::pipe();
+        round_trip(ChaosTransport::new(a, seed).fragment(), ChaosTransport::new(b, !seed).fragment());
+    }
+}
+
+#[test]
+fn delays() {
+    for seed in 0..SEEDS {
+        let (a, b) = testing::pipe();
+        round_trip(ChaosTransport::new(a, seed).delay(), ChaosTransport::new(b, !seed).delay().fragment());
+    }
+}
+
+#[test]
+fn corruption_is_detected() {
+    // Corrupting the high bytes of the proposal's length prefix makes it
+    // oversized, but the low bytes only shift where it ends and can leave
+    // both sides waiting on each other forever.
+    for offset in (0..2).chain(4..64) {
+        let (a, b) = testing::pipe();
+        assert!(handshake_fails(ChaosTransport::new(a, offset).corrupt(offset), ChaosTransport::new(b, 0)), "offset {}", offset);
+    }
+}
+
+#[test]
+fn disconnect_fails_handshake() {
+    for bytes in (0..512).filter(|bytes| bytes % 7 == 0) {
+        let (a, b) = testing::pipe();
+        assert!(handshake_fails(ChaosTransport::new(a, 0).disconnect(bytes), ChaosTransport::new(b, 0)), "after {} bytes", bytes);
+    }
+}
+
+#[test]
+fn reorders_writes_between_flushes() {
+    let reordered = (0..SEEDS).any(|seed| {
+        let (a, mut b) = testing::pipe();
+        let mut a = ChaosTransport::new(a, seed).reorder();
+        for byte in 0..8u8 {
+            a.write_all(&[byte]).unwrap();
+        }
+        assert_eq!(b.pending(), 0);
+        a.flush().unwrap();
+
+        let mut received = [0; 8];
+        b.read_exact(&mut received).unwrap();
+        let mut sorted = received;
+        sorted.sort();
+        assert_eq!(sorted, [0, 1, 2, 3, 4, 5, 6, 7]);
+        received != sorted
+    });
+    assert!(reordered);
+}
+
+#[test]
+fn intact_after_handshake() {
+    let (a, b) = testing::pipe();
+    assert_eq!(send(ChaosTransport::new(a, 0), b, 1).unwrap(), MESSAGE);
+}
+
+#[test]
+fn corruption_after_handshake_fails_mac() {
+    let layout = Layout::measure();
+    // Skip the length prefixes, corrupting those changes where frames end.
+    let offsets = [
+        layout.echo + 4, layout.echo_mac - 1, layout.echo_mac, layout.data - 1,
+        layout.data + 4, (layout.data + layout.data_mac) / 2, layout.data_mac - 1, layout.data_mac, layout.end - 1,
+    ];
+    for &offset in &offsets {
+        let (a, b) = testing::pipe();
+        let err = send(ChaosTransport::new(a, offset).corrupt(offset), b, 1).unwrap_err();
+        assert!(err.to_string().contains("MAC verification failed"), "offset {}: {:?}", offset, err);
+    }
+}
+
+#[test]
+fn disconnect_after_handshake_is_unexpected_eof() {
+    let layout = Layout::measure();
+    let offsets = [
+        layout.echo, layout.echo + 2, layout.echo + 4, layout.echo_mac, layout.data - 1,
+        layout.data, layout.data + 2, layout.data + 4, layout.data_mac, layout.end - 1,
+    ];
+    for &bytes in &offsets {
+        let (a, b) = testing::pipe();
+        let err = send(ChaosTransport::new(a, 0).disconnect(bytes), b, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "after {} bytes: {:?}", bytes, err);
+    }
+}
+
+#[test]
+fn reordered_frames_are_rejected() {
+    let layout = Layout::measure();
+    for seed in 0..SEEDS {
+        let (a, b) = testing::pipe();
+        // Shuffle the bytes of both data frames, leaving the handshake alone.
+        let a = ChaosTransport::new(a, seed).fragment().reorder().after(layout.data);
+        assert!(send(a, b, 2).is_err(), "seed {}", seed);
+    }
+}
@@ -0,0 +1,72 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{self, Write};
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::{flush, read_to_end, write_all};
+
+use secio::HandshakeConfig;
+use secio::testing;
+
+#[test]
+fn close_record_is_a_clean_eof() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    let _alice = core.run(testing::shutdown(alice)).unwrap();
+    let (_, data) = core.run(read_to_end(bob, Vec::new())).unwrap();
+    assert!(data.is_empty());
+}
+
+#[test]
+fn transport_eof_without_close_record_is_an_error() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    // Dropping closes the pipe without sending a close record.
+    drop(alice);
+    let err = core.run(read_to_end(bob, Vec::new())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("without a close record"), "{:?}", err);
+}
+
+#[test]
+fn peer_without_close_extension_gets_a_clean_eof() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new().legacy())).unwrap();
+
+    drop(bob);
+    let (_, data) = core.run(read_to_end(alice, Vec::new())).unwrap();
+    assert!(data.is_empty());
+}
+
+#[test]
+fn legacy_peer_reads_a_clean_eof() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new().legacy())).unwrap();
+
+    let _alice = core.run(testing::shutdown(alice)).unwrap();
+    let (_, data) = core.run(read_to_end(bob, Vec::new())).unwrap();
+    assert!(data.is_empty());
+}
+
+#[test]
+fn half_close_still_reads_the_response() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+
+    let alice = core.run(write_all(alice, b"request".to_vec()).and_then(|(alice, _)| testing::shutdown(alice))).unwrap();
+    let (bob, request) = core.run(read_to_end(bob, Vec::new())).unwrap();
+    assert_eq!(request, b"request");
+
+    // Writing after shutdown is refused, but leaves the read half working.
+    let mut alice = alice;
+    let err = alice.write(b"late").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+    let _bob = core.run(write_all(bob, b"response".to_vec()).and_then(|(bob, _)| flush(bob)).and_then(testing::shutdown)).unwrap();
+    let (_, response) = core.run(read_to_end(alice, Vec::new())).unwrap();
+    assert_eq!(response, b"response");
+}
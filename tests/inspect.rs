@@ -0,0 +1,138 @@
+extern crate futures_await as futures;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use futures::{Future, Poll};
+use identity::{HostId, PeerId};
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{flush, write_all};
+
+use secio::HandshakeConfig;
+use secio::inspect::{Session, inspect};
+use secio::testing::{self, Pipe};
+
+/// Keeps a copy of everything written to the pipe.
+struct Capture {
+    inner: Pipe,
+    written: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Read for Capture {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written.borrow_mut().extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl AsyncRead for Capture {
+}
+
+impl AsyncWrite for Capture {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+fn capture(inner: Pipe) -> (Capture, Rc<RefCell<Vec<u8>>>) {
+    let written = Rc::new(RefCell::new(Vec::new()));
+    (Capture { inner, written: written.clone() }, written)
+}
+
+/// What alice and bob each sent for a handshake followed by alice sending
+/// "hello", and the algorithms they negotiated.
+fn session() -> (Vec<u8>, Vec<u8>, String) {
+    let mut core = Core::new().unwrap();
+    let (a, b) = testing::pipe();
+    let ((a, alice_sent), (b, bob_sent)) = (capture(a), capture(b));
+    let (alice, _bob) = core.run(testing::connect_over(HandshakeConfig::new(), a, HandshakeConfig::new(), b)).unwrap();
+    let suite = format!("{} {} {}", alice.curve(), alice.cipher(), alice.hash());
+    core.run(write_all(alice, b"hello").and_then(|(alice, _)| flush(alice))).unwrap();
+    let (alice_sent, bob_sent) = (alice_sent.borrow().clone(), bob_sent.borrow().clone());
+    (alice_sent, bob_sent, suite)
+}
+
+fn id(host: HostId) -> PeerId {
+    PeerId::from_protobuf(&host.pub_key().to_protobuf().unwrap()).unwrap()
+}
+
+fn assert_invalid(result: io::Result<Session>, message: &str) {
+    let err = result.expect_err("capture was accepted");
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", err);
+    assert!(err.to_string().contains(message), "expected {:?}, got {:?}", message, err);
+}
+
+/// The offset just past the length prefixed message at `index`.
+fn end_of_message(capture: &[u8], index: usize) -> usize {
+    let mut offset = 0;
+    for _ in 0..index + 1 {
+        let len = capture[offset..offset + 4].iter().fold(0, |len, &byte| len << 8 | byte as usize);
+        offset += 4 + len;
+    }
+    offset
+}
+
+#[test]
+fn inspects_a_session() {
+    let (alice_sent, bob_sent, suite) = session();
+    let session = inspect(&alice_sent, &bob_sent).unwrap();
+
+    assert!(session.peers[0].matches(&id(testing::alice())), "{:?}", session.peers);
+    assert!(session.peers[1].matches(&id(testing::bob())), "{:?}", session.peers);
+    assert_eq!(format!("{} {} {}", session.curve, session.cipher, session.hash), suite);
+    assert!(session.typed_records);
+
+    // Each side's nonce echo, then alice's "hello".
+    assert_eq!(session.frames[0].len(), 2);
+    assert_eq!(session.frames[1].len(), 1);
+}
+
+#[test]
+fn captures_in_either_order() {
+    let (alice_sent, bob_sent, _) = session();
+    let session = inspect(&bob_sent, &alice_sent).unwrap();
+    assert!(session.peers[0].matches(&id(testing::bob())), "{:?}", session.peers);
+    assert!(session.peers[1].matches(&id(testing::alice())), "{:?}", session.peers);
+    assert_eq!(session.frames[0].len(), 1);
+    assert_eq!(session.frames[1].len(), 2);
+}
+
+#[test]
+fn truncated_capture() {
+    let (alice_sent, bob_sent, _) = session();
+    assert_invalid(inspect(&alice_sent[..alice_sent.len() - 1], &bob_sent), "only");
+    assert_invalid(inspect(&alice_sent[..2], &bob_sent), "length prefix");
+}
+
+#[test]
+fn capture_of_part_of_the_handshake() {
+    let (alice_sent, bob_sent, _) = session();
+    let proposal_only = end_of_message(&bob_sent, 0);
+    assert_invalid(inspect(&alice_sent, &bob_sent[..proposal_only]), "capture 1 ends before the handshake does");
+}
+
+#[test]
+fn tampered_exchange() {
+    let (alice_sent, mut bob_sent, _) = session();
+    // The signature is the last field of the exchange.
+    let last = end_of_message(&bob_sent, 1) - 1;
+    bob_sent[last] ^= 1;
+    assert_invalid(inspect(&alice_sent, &bob_sent), "exchange signature from side 1 did not verify");
+}
@@ -0,0 +1,106 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+
+use std::io;
+
+use tokio_core::reactor::Core;
+
+use secio::testing::{Misbehaviour, misbehave};
+
+fn error(misbehaviour: Misbehaviour) -> io::Error {
+    let mut core = Core::new().unwrap();
+    core.run(misbehave(misbehaviour)).expect_err("misbehaving peer was accepted")
+}
+
+fn assert_error(misbehaviour: Misbehaviour, message: &str) {
+    let err = error(misbehaviour);
+    assert!(err.to_string().contains(message), "{:?}: expected {:?}, got {:?}", misbehaviour, message, err);
+}
+
+#[test]
+fn disconnect() {
+    assert_error(Misbehaviour::Disconnect, "Unexpected EOF");
+}
+
+#[test]
+fn malformed_proposal() {
+    assert_error(Misbehaviour::MalformedProposal, "invalid proposal");
+}
+
+#[test]
+fn oversized_proposal() {
+    assert_error(Misbehaviour::OversizedProposal, "handshake message of 16777216 bytes exceeds maximum");
+}
+
+#[test]
+fn no_common_algorithms() {
+    assert_error(Misbehaviour::NoCommonAlgorithms, "couldn't select a common exchange");
+}
+
+#[test]
+fn mismatched_pubkey() {
+    assert_error(Misbehaviour::MismatchedPubkey, "didn't match provided id");
+}
+
+#[test]
+fn reflect_proposal() {
+    assert_error(Misbehaviour::ReflectProposal, "talking to self");
+}
+
+#[test]
+fn bad_signature() {
+    assert_error(Misbehaviour::BadSignature, "exchange signature verification failed");
+}
+
+#[test]
+fn wrong_nonce() {
+    assert_error(Misbehaviour::WrongNonce, "Nonces did not match");
+}
+
+#[test]
+fn oversized_frame() {
+    assert_error(Misbehaviour::OversizedFrame, "exceeds maximum");
+}
+
+#[test]
+fn short_frame() {
+    assert_error(Misbehaviour::ShortFrame, "too short to contain a MAC");
+}
+
+#[test]
+fn flipped_mac() {
+    assert_error(Misbehaviour::FlippedMac, "MAC verification failed on inbound frame #1");
+}
+
+#[test]
+fn untyped_record() {
+    assert_error(Misbehaviour::UntypedRecord, "received record without a type");
+}
+
+#[test]
+fn unknown_record() {
+    assert_error(Misbehaviour::UnknownRecord, "received record of unknown type");
+}
+
+#[test]
+fn unexpected_rekey_ack() {
+    assert_error(Misbehaviour::UnexpectedRekeyAck, "received unexpected rekey acknowledgement");
+}
+
+#[test]
+fn duplicate_rekey() {
+    assert_error(Misbehaviour::DuplicateRekey, "received rekey record while already rekeying");
+}
+
+#[test]
+fn unnegotiated_rekey() {
+    assert_error(Misbehaviour::UnnegotiatedRekey, "received rekey record without negotiating rekeying");
+}
+
+#[test]
+fn truncated() {
+    let err = error(Misbehaviour::Truncated);
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("partway through a frame"), "got {:?}", err);
+}
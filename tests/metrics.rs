@@ -0,0 +1,104 @@
+extern crate futures_await as futures;
+extern crate libp2p_crypto as crypto;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use futures::Future;
+use identity::PeerId;
+use tokio_core::reactor::Core;
+use tokio_io::io::{flush, read_exact, write_all};
+
+use crypto::CipherAlgorithm;
+use secio::{HandshakeConfig, MetricsRecorder, PrometheusRecorder};
+use secio::testing::{self, ChaosTransport};
+
+#[test]
+fn handshake_and_traffic() {
+    let recorder = PrometheusRecorder::new();
+    let config = HandshakeConfig::new().metrics(recorder.clone());
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(config, HandshakeConfig::new())).unwrap();
+
+    let write = write_all(alice, vec![0; 100]).and_then(|(alice, _)| flush(alice));
+    let (alice, _) = core.run(write.join(read_exact(bob, vec![0; 100]))).unwrap();
+
+    let rendered = recorder.render();
+    assert!(rendered.contains("# TYPE secio_handshakes_started_total counter\nsecio_handshakes_started_total 1\n"), "{}", rendered);
+    assert!(rendered.contains("secio_handshakes_succeeded_total 1\n"), "{}", rendered);
+    assert!(rendered.contains("# TYPE secio_handshake_duration_seconds histogram\n"), "{}", rendered);
+    assert!(rendered.contains("secio_handshake_duration_seconds_count 1\n"), "{}", rendered);
+    assert!(rendered.contains("secio_negotiated_suites_total{curve="), "{}", rendered);
+    // The nonce echo and the data frame.
+    assert!(rendered.contains("secio_frames_encrypted_total 2\n"), "{}", rendered);
+    assert!(rendered.contains("secio_frames_decrypted_total 1\n"), "{}", rendered);
+    // Byte counts agree with the stream's own, leaving out record types.
+    let stats = alice.stats();
+    assert!(rendered.contains(&format!("secio_bytes_encrypted_total {}\n", stats.plaintext_sent)), "{}", rendered);
+    assert!(rendered.contains(&format!("secio_bytes_decrypted_total {}\n", stats.plaintext_received)), "{}", rendered);
+}
+
+#[test]
+fn failed_handshake() {
+    let recorder = PrometheusRecorder::new();
+    let config = HandshakeConfig::new().metrics(recorder.clone());
+    let (a, b) = testing::pipe();
+    let mut core = Core::new().unwrap();
+    let result = core.run(testing::connect_over(config, ChaosTransport::new(a, 0).disconnect(0), HandshakeConfig::new(), b));
+    assert!(result.is_err());
+
+    let rendered = recorder.render();
+    assert!(rendered.contains("secio_handshakes_failed_total{kind=\"io\"} 1\n"), "{}", rendered);
+    assert!(!rendered.contains("secio_handshakes_succeeded_total"), "{}", rendered);
+}
+
+#[test]
+fn failures_are_classified() {
+    let recorder = PrometheusRecorder::new();
+    let mut core = Core::new().unwrap();
+
+    // The peer disconnects before sending its proposal.
+    let (a, b) = testing::pipe();
+    drop(b);
+    let config = HandshakeConfig::new().metrics(recorder.clone());
+    assert!(core.run(secio::handshake(config, testing::parts(a), testing::alice(), PeerId::Unknown)).is_err());
+
+    // Both sides share the recorder, as only the first to fail may finish.
+    let ciphers = CipherAlgorithm::all();
+    let alice = HandshakeConfig::new().metrics(recorder.clone()).ciphers(vec![ciphers[0]]);
+    let bob = HandshakeConfig::new().metrics(recorder.clone()).ciphers(vec![ciphers[1]]);
+    assert!(core.run(testing::connect(alice, bob)).is_err());
+
+    let rendered = recorder.render();
+    assert!(rendered.contains("secio_handshakes_failed_total{kind=\"eof\"} 1\n"), "{}", rendered);
+    assert!(rendered.contains("secio_handshakes_failed_total{kind=\"no_common_algorithms\"}"), "{}", rendered);
+}
+
+#[test]
+fn renders_prometheus_text() {
+    let recorder = PrometheusRecorder::new();
+    recorder.increment("requests_total", &[("path", "a \"quoted\" path")], 2);
+    recorder.observe("latency_seconds", &[], 0.003);
+    assert_eq!(recorder.render(), concat!(
+        "# TYPE latency_seconds histogram\n",
+        "latency_seconds_bucket{le=\"0.001\"} 0\n",
+        "latency_seconds_bucket{le=\"0.0025\"} 0\n",
+        "latency_seconds_bucket{le=\"0.005\"} 1\n",
+        "latency_seconds_bucket{le=\"0.01\"} 1\n",
+        "latency_seconds_bucket{le=\"0.025\"} 1\n",
+        "latency_seconds_bucket{le=\"0.05\"} 1\n",
+        "latency_seconds_bucket{le=\"0.1\"} 1\n",
+        "latency_seconds_bucket{le=\"0.25\"} 1\n",
+        "latency_seconds_bucket{le=\"0.5\"} 1\n",
+        "latency_seconds_bucket{le=\"1\"} 1\n",
+        "latency_seconds_bucket{le=\"2.5\"} 1\n",
+        "latency_seconds_bucket{le=\"5\"} 1\n",
+        "latency_seconds_bucket{le=\"10\"} 1\n",
+        "latency_seconds_bucket{le=\"+Inf\"} 1\n",
+        "latency_seconds_sum 0.003\n",
+        "latency_seconds_count 1\n",
+        "# TYPE requests_total counter\n",
+        "requests_total{path=\"a \\\"quoted\\\" path\"} 2\n",
+    ));
+}
@@ -0,0 +1,66 @@
+extern crate futures_await as futures;
+extern crate libp2p_crypto as crypto;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use identity::PeerId;
+use tokio_core::reactor::Core;
+
+use crypto::{HashAlgorithm, CipherAlgorithm, CurveAlgorithm};
+use secio::{HandshakeConfig, HandshakeObserver};
+use secio::testing::{self, ChaosTransport};
+
+#[derive(Clone, Debug, Default)]
+struct Recorder(Arc<Mutex<Vec<&'static str>>>);
+
+impl Recorder {
+    fn record(&self, event: &'static str) {
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn events(&self) -> Vec<&'static str> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl HandshakeObserver for Recorder {
+    fn proposal_sent(&self, _: Duration) { self.record("proposal sent") }
+    fn proposal_received(&self, _: Duration) { self.record("proposal received") }
+    fn peer_identified(&self, _: &PeerId, _: Duration) { self.record("peer identified") }
+    fn algorithms_selected(&self, _: CurveAlgorithm, _: CipherAlgorithm, _: HashAlgorithm, _: Duration) { self.record("algorithms selected") }
+    fn exchange_verified(&self, _: Duration) { self.record("exchange verified") }
+    fn finished(&self, _: &PeerId, _: Duration) { self.record("finished") }
+    fn failed(&self, _: &io::Error, _: Duration) { self.record("failed") }
+}
+
+#[test]
+fn successful_handshake() {
+    let recorder = Recorder::default();
+    let config = HandshakeConfig::new().observer(recorder.clone());
+    let mut core = Core::new().unwrap();
+    core.run(testing::connect(config, HandshakeConfig::new())).unwrap();
+    assert_eq!(recorder.events(), [
+        "proposal sent",
+        "proposal received",
+        "peer identified",
+        "algorithms selected",
+        "exchange verified",
+        "finished",
+    ]);
+}
+
+#[test]
+fn failed_handshake() {
+    let recorder = Recorder::default();
+    let config = HandshakeConfig::new().observer(recorder.clone());
+    let (a, b) = testing::pipe();
+    let mut core = Core::new().unwrap();
+    let result = core.run(testing::connect_over(config, ChaosTransport::new(a, 0).disconnect(0), HandshakeConfig::new(), b));
+    assert!(result.is_err());
+    assert_eq!(recorder.events(), ["failed"]);
+}
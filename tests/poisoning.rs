@@ -0,0 +1,49 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{self, Read, Write};
+
+use futures::{Future, executor, future};
+use tokio_core::reactor::Core;
+use tokio_io::AsyncWrite;
+use tokio_io::io::{flush, read, write_all};
+
+use secio::HandshakeConfig;
+use secio::testing;
+
+/// Run `f` as a task, so the stream may register interest if it would
+/// block.
+fn in_task<T, F: FnOnce() -> T>(f: F) -> T {
+    executor::spawn(future::lazy(|| Ok::<T, ()>(f()))).wait_future().unwrap()
+}
+
+#[test]
+fn mac_failure_poisons_the_stream() {
+    let mut core = Core::new().unwrap();
+    let (alice, mut bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    let alice = core.run(write_all(alice, b"hello").and_then(|(alice, _)| flush(alice))).unwrap();
+
+    let last_bit = bob.get_ref().pending() * 8 - 1;
+    bob.get_ref().flip_bit(last_bit);
+
+    let first = in_task(|| bob.read(&mut [0; 5])).unwrap_err();
+    assert!(first.to_string().contains("MAC verification failed"), "{:?}", first);
+    assert_eq!(testing::failure(&first), "mac");
+
+    let check = |err: io::Error| {
+        assert_eq!(err.kind(), first.kind());
+        assert_eq!(err.to_string(), first.to_string());
+        assert_eq!(testing::failure(&err), "mac");
+    };
+    check(in_task(|| bob.read(&mut [0; 5])).unwrap_err());
+    check(in_task(|| bob.write(b"more")).unwrap_err());
+    check(in_task(|| bob.flush()).unwrap_err());
+    check(in_task(|| bob.shutdown()).unwrap_err());
+
+    // Bob shut down the transport without a close record.
+    let err = core.run(read(alice, vec![0; 1])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("without a close record"), "{:?}", err);
+}
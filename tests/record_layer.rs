@@ -0,0 +1,128 @@
+extern crate futures_await as futures;
+extern crate libp2p_crypto as crypto;
+extern crate libp2p_secio as secio;
+#[macro_use]
+extern crate proptest;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::Cursor;
+
+use futures::{Async, Future, future};
+use proptest::prelude::*;
+use tokio_core::reactor::Core;
+use tokio_io::AsyncWrite;
+use tokio_io::io::{flush, read_exact, write_all};
+
+use crypto::{HashAlgorithm, CipherAlgorithm};
+use secio::{HandshakeConfig, SecStream};
+use secio::testing::{self, Pipe};
+
+/// Every cipher and hash pairing, each property is checked against all of
+/// them rather than a random one per case.
+fn suites() -> Vec<(CipherAlgorithm, HashAlgorithm)> {
+    CipherAlgorithm::all().iter()
+        .flat_map(|&cipher| HashAlgorithm::all().iter().map(move |&hash| (cipher, hash)))
+        .collect()
+}
+
+fn connect(cipher: CipherAlgorithm, hash: HashAlgorithm) -> (Core, SecStream<Pipe>, SecStream<Pipe>) {
+    let mut core = Core::new().unwrap();
+    let config = HandshakeConfig::new()
+        .ciphers(vec![cipher])
+        .hashes(vec![hash]);
+    let (alice, bob) = core.run(testing::connect(config.clone(), config)).unwrap();
+    (core, alice, bob)
+}
+
+/// The largest frame either side will send or accept, excluding its length
+/// prefix.
+const MAX_FRAME_LEN: u64 = 8 * 1024 * 1024;
+
+/// More data than fits in a single frame.
+fn oversized() -> Vec<u8> {
+    (0..MAX_FRAME_LEN as usize + 1024 * 1024).map(|i| i as u8).collect()
+}
+
+/// Read `expected` back on `bob`, checking it was split into frames small
+/// enough for the peer to accept.
+fn assert_split(core: &mut Core, bob: &mut SecStream<Pipe>, expected: &[u8]) {
+    let (_, actual) = core.run(read_exact(&mut *bob, vec![0; expected.len()])).unwrap();
+    assert!(actual == expected, "data was corrupted");
+    let stats = bob.stats();
+    assert!(stats.largest_frame <= 4 + MAX_FRAME_LEN, "received a {} byte frame", stats.largest_frame);
+    // The nonce echo plus at least two frames of data.
+    assert!(stats.frames_received >= 3, "received {} frames", stats.frames_received);
+}
+
+#[test]
+fn large_writes_are_split() {
+    let (mut core, mut alice, mut bob) = connect(CipherAlgorithm::all()[0], HashAlgorithm::all()[0]);
+    let data = oversized();
+    core.run(write_all(&mut alice, &data[..]).and_then(|(alice, _)| flush(alice))).unwrap();
+    assert_split(&mut core, &mut bob, &data);
+}
+
+#[test]
+fn large_vectored_writes_are_split() {
+    let (mut core, mut alice, mut bob) = connect(CipherAlgorithm::all()[0], HashAlgorithm::all()[0]);
+    let data = oversized();
+    let mut buf = Cursor::new(&data[..]);
+    core.run(future::poll_fn(|| {
+        while buf.position() < data.len() as u64 {
+            if let Async::NotReady = alice.write_buf(&mut buf)? {
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok::<_, ::std::io::Error>(Async::Ready(()))
+    })).unwrap();
+    core.run(flush(&mut alice)).unwrap();
+    assert_split(&mut core, &mut bob, &data);
+}
+
+fn writes() -> BoxedStrategy<Vec<(Vec<u8>, bool)>> {
+    prop::collection::vec((prop::collection::vec(any::<u8>(), 0..4096), any::<bool>()), 1..16).boxed()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn writes_are_read_back_unchanged(writes in writes()) {
+        for (cipher, hash) in suites() {
+            let (mut core, mut alice, mut bob) = connect(cipher, hash);
+
+            let mut expected = Vec::new();
+            for &(ref data, should_flush) in &writes {
+                core.run(write_all(&mut alice, &data[..])).unwrap();
+                if should_flush {
+                    core.run(flush(&mut alice)).unwrap();
+                }
+                expected.extend_from_slice(data);
+            }
+            core.run(flush(&mut alice)).unwrap();
+
+            let (_, actual) = core.run(read_exact(&mut bob, vec![0; expected.len()])).unwrap();
+            prop_assert_eq!(actual, expected, "with {} and {}", cipher, hash);
+        }
+    }
+
+    #[test]
+    fn flipped_bits_fail_mac_verification(
+            data in prop::collection::vec(any::<u8>(), 1..4096),
+            bit in any::<usize>()) {
+        for (cipher, hash) in suites() {
+            let (mut core, mut alice, mut bob) = connect(cipher, hash);
+
+            core.run(write_all(&mut alice, &data[..]).and_then(|(alice, _)| flush(alice))).unwrap();
+
+            // Skip the length prefix, corrupting it changes where the frame
+            // ends rather than its contents.
+            let frame_bits = (bob.get_ref().pending() - 4) * 8;
+            bob.get_ref().flip_bit(32 + bit % frame_bits);
+
+            let err = core.run(read_exact(&mut bob, vec![0; data.len()])).unwrap_err();
+            prop_assert!(err.to_string().contains("MAC verification failed"), "unexpected error with {} and {}: {}", cipher, hash, err);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::{flush, read_exact, read_to_end, write_all};
+
+use secio::{HandshakeConfig, RekeyLimits, SecStream};
+use secio::testing::{self, Pipe};
+
+fn connect(core: &mut Core, alice: RekeyLimits, bob: Option<RekeyLimits>) -> (SecStream<Pipe>, SecStream<Pipe>) {
+    let alice = HandshakeConfig::new().rekey(alice);
+    let bob = match bob {
+        Some(limits) => HandshakeConfig::new().rekey(limits),
+        None => HandshakeConfig::new(),
+    };
+    core.run(testing::connect(alice, bob)).unwrap()
+}
+
+fn write(core: &mut Core, stream: SecStream<Pipe>, data: &[u8]) -> SecStream<Pipe> {
+    core.run(write_all(stream, data.to_owned()).and_then(|(stream, _)| flush(stream))).unwrap()
+}
+
+fn read(core: &mut Core, stream: SecStream<Pipe>, expected: &[u8]) -> SecStream<Pipe> {
+    let (stream, data) = core.run(read_exact(stream, vec![0; expected.len()])).unwrap();
+    assert_eq!(data, expected);
+    stream
+}
+
+/// Send `data` from `from` and check it arrives at `to`.
+fn ping(core: &mut Core, from: SecStream<Pipe>, to: SecStream<Pipe>, data: &[u8]) -> (SecStream<Pipe>, SecStream<Pipe>) {
+    let from = write(core, from, data);
+    let to = read(core, to, data);
+    (from, to)
+}
+
+/// Runs the exchange started by alice's next write to completion on both
+/// sides, checking data sent under each set of keys arrives intact.
+fn rekey_once(core: &mut Core, alice: SecStream<Pipe>, bob: SecStream<Pipe>) {
+    // Alice sends her key, bob replies with his and his acknowledgement.
+    let (alice, bob) = ping(core, alice, bob, b"first");
+    // Alice acknowledges, then reads bob's acknowledgement.
+    let (bob, alice) = ping(core, bob, alice, b"second");
+    // Bob reads alice's acknowledgement.
+    let (alice, bob) = ping(core, alice, bob, b"third");
+
+    assert_eq!(alice.stats().rekeys, 1);
+    assert_eq!(bob.stats().rekeys, 1);
+}
+
+#[test]
+fn byte_limit() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = connect(&mut core, RekeyLimits { bytes: Some(100), ..RekeyLimits::default() }, None);
+    let (alice, bob) = ping(&mut core, alice, bob, &[1; 200]);
+    assert_eq!(alice.stats().rekeys, 0);
+    rekey_once(&mut core, alice, bob);
+}
+
+#[test]
+fn frame_limit() {
+    let mut core = Core::new().unwrap();
+    // The nonce echo is the first frame.
+    let (alice, bob) = connect(&mut core, RekeyLimits { frames: Some(2), ..RekeyLimits::default() }, None);
+    let (alice, bob) = ping(&mut core, alice, bob, b"zeroth");
+    assert_eq!(alice.stats().rekeys, 0);
+    rekey_once(&mut core, alice, bob);
+}
+
+#[test]
+fn interval_limit() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = connect(&mut core, RekeyLimits { interval: Some(Duration::from_millis(10)), ..RekeyLimits::default() }, None);
+    thread::sleep(Duration::from_millis(20));
+    rekey_once(&mut core, alice, bob);
+}
+
+#[test]
+fn both_sides_start_at_once() {
+    let mut core = Core::new().unwrap();
+    let limits = RekeyLimits { frames: Some(1), ..RekeyLimits::default() };
+    let (alice, bob) = connect(&mut core, limits, Some(limits));
+
+    // Both send their key before seeing the other's.
+    let alice = write(&mut core, alice, b"from alice");
+    let bob = write(&mut core, bob, b"from bob");
+    // Each reads the other's key and acknowledges.
+    let alice = read(&mut core, alice, b"from bob");
+    let bob = read(&mut core, bob, b"from alice");
+    // Each reads the other's acknowledgement.
+    let (alice, bob) = ping(&mut core, alice, bob, b"after alice");
+    let (bob, alice) = ping(&mut core, bob, alice, b"after bob");
+
+    assert_eq!(alice.stats().rekeys, 1);
+    assert_eq!(bob.stats().rekeys, 1);
+}
+
+#[test]
+fn data_around_acknowledgements() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = connect(&mut core, RekeyLimits { frames: Some(1), ..RekeyLimits::default() }, None);
+
+    // Alice's key, then data under the old keys.
+    let (alice, bob) = ping(&mut core, alice, bob, b"before either");
+    // Bob has sent his key and acknowledgement, this uses the next keys.
+    let bob = write(&mut core, bob, b"after bob's");
+    // Alice hasn't seen bob's key yet, so this still uses the old keys and
+    // reaches bob between the two acknowledgements.
+    let alice = write(&mut core, alice, b"between");
+    // Alice acknowledges, then reads bob's acknowledgement and the data
+    // after it.
+    let alice = read(&mut core, alice, b"after bob's");
+    let alice = write(&mut core, alice, b"after alice's");
+    let bob = read(&mut core, bob, b"between");
+    assert_eq!(bob.stats().rekeys, 0);
+    let bob = read(&mut core, bob, b"after alice's");
+
+    assert_eq!(alice.stats().rekeys, 1);
+    assert_eq!(bob.stats().rekeys, 1);
+}
+
+#[test]
+fn rekey_after_close() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = connect(&mut core, RekeyLimits { frames: Some(1), ..RekeyLimits::default() }, None);
+
+    let bob = core.run(testing::shutdown(bob)).unwrap();
+    // Alice's key arrives after bob's close, bob can't answer it but still
+    // reads the data after it.
+    let (alice, bob) = ping(&mut core, alice, bob, b"after close");
+    let (_, data) = core.run(read_to_end(alice, Vec::new())).unwrap();
+    assert!(data.is_empty());
+    assert_eq!(bob.stats().rekeys, 0);
+}
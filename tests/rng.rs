@@ -0,0 +1,38 @@
+extern crate futures_await as futures;
+extern crate libp2p_identity as identity;
+extern crate libp2p_secio as secio;
+
+use std::io::Read;
+
+use futures::{Future, executor, future};
+use identity::PeerId;
+
+use secio::HandshakeConfig;
+use secio::testing::{self, SeededRng};
+
+/// The length prefixed proposal sent by a handshake using a `SeededRng`.
+fn proposal(seed: u64) -> Vec<u8> {
+    let (a, mut b) = testing::pipe();
+    let config = HandshakeConfig::new().rng(SeededRng::new(seed));
+    let mut handshake = secio::handshake(config, testing::parts(a), testing::alice(), PeerId::Unknown);
+
+    // The proposal is sent before anything is read from the peer.
+    let polled = executor::spawn(future::lazy(|| Ok::<_, ()>(handshake.poll()))).wait_future().unwrap();
+    assert!(polled.unwrap().is_not_ready());
+
+    let mut sent = vec![0; b.pending()];
+    b.read_exact(&mut sent).unwrap();
+    sent
+}
+
+#[test]
+fn same_seed_gives_identical_proposals() {
+    let first = proposal(42);
+    assert!(!first.is_empty());
+    assert_eq!(first, proposal(42));
+}
+
+#[test]
+fn different_seeds_give_different_proposals() {
+    assert_ne!(proposal(42), proposal(43));
+}
@@ -0,0 +1,64 @@
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+/// Cargo builds the binaries next to the directory holding this test.
+fn secio_cat() -> Command {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    Command::new(path.join("secio-cat"))
+}
+
+fn key(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/keys").join(name)
+}
+
+fn spawn(args: &[&str], identity: &str) -> Child {
+    secio_cat()
+        .args(args)
+        .arg(key(&format!("{}.der", identity)))
+        .arg(key(&format!("{}.pub.der", identity)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("secio-cat should have been built for the integration tests")
+}
+
+/// Send `input` as the whole of stdin.
+fn send(child: &mut Child, input: &[u8]) {
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input).unwrap();
+}
+
+#[test]
+fn loopback() {
+    let mut listener = spawn(&["-l", "127.0.0.1:0"], "alice");
+
+    let mut stderr = BufReader::new(listener.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let addr = line.trim().trim_left_matches("listening on ").to_owned();
+    assert!(line.starts_with("listening on "), "{:?}", line);
+
+    let mut dialer = spawn(&[&addr], "bob");
+
+    // Each side only exits once both have sent everything and shut down.
+    send(&mut dialer, b"from bob\n");
+    send(&mut listener, b"from alice\n");
+    let dialed = dialer.wait_with_output().unwrap();
+    let listened = listener.wait_with_output().unwrap();
+
+    assert!(dialed.status.success(), "{}", String::from_utf8_lossy(&dialed.stderr));
+    assert!(listened.status.success(), "{:?}", listened.status);
+    assert_eq!(String::from_utf8_lossy(&dialed.stdout), "from alice\n");
+    assert_eq!(String::from_utf8_lossy(&listened.stdout), "from bob\n");
+
+    let dialed_log = String::from_utf8_lossy(&dialed.stderr);
+    assert!(dialed_log.contains("connected to"), "{}", dialed_log);
+    assert!(dialed_log.contains("curve: "), "{}", dialed_log);
+}
@@ -0,0 +1,45 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::{flush, read_exact, write_all};
+
+use secio::HandshakeConfig;
+use secio::testing;
+
+/// The size of the nonce each side echoes at the end of the handshake.
+const NONCE: u64 = 16;
+
+#[test]
+fn counts_both_directions() {
+    let mut core = Core::new().unwrap();
+    let (alice, bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+
+    let write = write_all(alice, vec![0; 100]).and_then(|(alice, _)| flush(alice));
+    let (alice, (bob, _)) = core.run(write.join(read_exact(bob, vec![0; 100]))).unwrap();
+
+    let sent = alice.stats();
+    let received = bob.stats();
+    assert_eq!(sent.plaintext_sent, NONCE + 100);
+    assert_eq!(received.plaintext_received, NONCE + 100);
+    assert_eq!(sent.frames_sent, 2);
+    assert_eq!(received.frames_received, 2);
+    assert_eq!(sent.ciphertext_sent, received.ciphertext_received);
+    assert!(sent.ciphertext_sent > sent.plaintext_sent);
+    assert!(sent.largest_frame > 100);
+    assert_eq!(sent.largest_frame, received.largest_frame);
+}
+
+#[test]
+fn idle_time() {
+    let mut core = Core::new().unwrap();
+    let (alice, _bob) = core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    assert!(alice.stats().idle >= Duration::from_millis(20));
+}
@@ -0,0 +1,60 @@
+extern crate futures_await as futures;
+extern crate libp2p_secio as secio;
+extern crate tokio_core;
+extern crate tracing;
+
+use std::sync::{Arc, Mutex};
+
+use tokio_core::reactor::Core;
+use tracing::{Event, Metadata, Subscriber};
+use tracing::span::{Attributes, Id, Record};
+
+use secio::HandshakeConfig;
+use secio::testing;
+
+/// Keeps the names of every span created and the number of events.
+#[derive(Clone, Debug, Default)]
+struct Recorder {
+    spans: Arc<Mutex<Vec<&'static str>>>,
+    events: Arc<Mutex<usize>>,
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes) -> Id {
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(attributes.metadata().name());
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _: &Id, _: &Record) {
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {
+    }
+
+    fn event(&self, _: &Event) {
+        *self.events.lock().unwrap() += 1;
+    }
+
+    fn enter(&self, _: &Id) {
+    }
+
+    fn exit(&self, _: &Id) {
+    }
+}
+
+#[test]
+fn handshake_emits_span_and_events() {
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        let mut core = Core::new().unwrap();
+        core.run(testing::connect(HandshakeConfig::new(), HandshakeConfig::new())).unwrap();
+    });
+
+    assert!(recorder.spans.lock().unwrap().contains(&"secio_handshake"), "{:?}", recorder.spans);
+    assert!(*recorder.events.lock().unwrap() > 0);
+}